@@ -0,0 +1,214 @@
+use std::sync::Arc;
+
+use glam::*;
+use crate::hitable::HitRecord;
+use crate::material::*;
+use crate::math::{coordinate_system, Ray};
+use crate::texture::Texture;
+use crate::volume::Medium;
+
+/// `inner` seen through a perturbed shading normal. Directions are converted between the frame of the hit
+/// and the perturbed one, and those the perturbed normal puts on the other side of the surface than
+/// the geometric normal are dropped, which would otherwise leak light through it at grazing angles.
+struct Perturbed<'a> {
+    inner: &'a dyn Material,
+    rec: &'a HitRecord,
+    shading: HitRecord,
+}
+
+impl<'a> Perturbed<'a> {
+    /// `outward` is the new normal on the outside of the surface, in world space.
+    fn new(inner: &'a dyn Material, rec: &'a HitRecord, outward: Vec3A) -> Self {
+        let norm = if rec.front_face { outward } else { -outward };
+        let tang = rec.tang - norm * norm.dot(rec.tang);
+        let tang = if tang.length_squared() > 1e-12 { tang.normalize() } else { coordinate_system(norm) };
+        Self { inner, rec, shading: HitRecord { norm, tang, ..rec.clone() } }
+    }
+
+    fn to_shading(&self, v: Vec3A) -> Vec3A {
+        self.shading.world_to_local(self.rec.local_to_world(v))
+    }
+
+    fn to_hit(&self, v: Vec3A) -> Vec3A {
+        self.rec.world_to_local(self.shading.local_to_world(v))
+    }
+
+    /// Whether `w` in the frame of the hit and `ws` in the shading frame, the same direction,
+    /// are on the same side of the geometric and of the shading normal.
+    fn same_side(&self, w: Vec3A, ws: Vec3A) -> bool {
+        (self.rec.local_to_world(w).dot(self.rec.geom_norm) > 0.) == (ws.z > 0.)
+    }
+
+    fn eval(&self, wi: Vec3A, wo: Vec3A) -> Vec3A {
+        let (wis, wos) = (self.to_shading(wi), self.to_shading(wo));
+        // seen from behind the perturbed normal, fall back to the plain surface
+        if wis.z <= 0. {
+            return self.inner.eval(self.rec, wi, wo);
+        }
+        if !self.same_side(wo, wos) {
+            return Vec3A::ZERO;
+        }
+        self.inner.eval(&self.shading, wis, wos)
+    }
+
+    fn sample(&self, wi: Vec3A, uc: f32, u: Vec2) -> Option<BsdfSample> {
+        let wis = self.to_shading(wi);
+        if wis.z <= 0. {
+            return self.inner.sample(self.rec, wi, uc, u);
+        }
+        let bs = self.inner.sample(&self.shading, wis, uc, u)?;
+        let wo = self.to_hit(bs.wo);
+        if !self.same_side(wo, bs.wo) {
+            return None;
+        }
+        Some(BsdfSample { wo, ..bs })
+    }
+
+    fn pdf(&self, wi: Vec3A, wo: Vec3A) -> f32 {
+        let (wis, wos) = (self.to_shading(wi), self.to_shading(wo));
+        if wis.z <= 0. {
+            return self.inner.pdf(self.rec, wi, wo);
+        }
+        if !self.same_side(wo, wos) {
+            return 0.;
+        }
+        self.inner.pdf(&self.shading, wis, wos)
+    }
+}
+
+/// Unit directions along the surface that `u` and `v` grow in at `rec`, and the lengths of dp/du and dp/dv.
+/// The one of `v` need not be orthogonal to `u` nor right-handed with `outward`.
+/// Shapes without dp/du and dp/dv fall back to `tang`, `outward.cross(tang)` and unit lengths.
+fn uv_axes(rec: &HitRecord, outward: Vec3A) -> (Vec3A, Vec3A, Vec2) {
+    let tu = rec.tang;
+    let tv = (rec.dpdv - outward * outward.dot(rec.dpdv)).try_normalize()
+        .filter(|tv| tv.cross(tu).length_squared() > 1e-6);
+    let len_u = if rec.dpdu == Vec3A::ZERO { 1. } else { rec.dpdu.length() };
+    match tv {
+        Some(tv) => (tu, tv, vec2(len_u, rec.dpdv.length())),
+        None => (tu, outward.cross(tu), vec2(len_u, 1.)),
+    }
+}
+
+/// Tangent space normal map over `inner`, usually an `ImageTex` of RGB in `[0, 1]` read as `2 * rgb - 1`.
+/// The map's x follows `HitRecord::tang`, y the bitangent `norm.cross(tang)` flipped to the side `v` grows to,
+/// and z the outward normal.
+pub struct NormalMap {
+    pub inner: Arc<dyn Material>,
+    pub map: Arc<dyn Texture>,
+}
+
+impl NormalMap {
+    fn perturbed<'a>(&'a self, rec: &'a HitRecord) -> Perturbed<'a> {
+        let outward = if rec.front_face { rec.norm } else { -rec.norm };
+        let (tu, tv, _) = uv_axes(rec, outward);
+        let bitang = outward.cross(tu);
+        let bitang = if bitang.dot(tv) < 0. { -bitang } else { bitang };
+        let n = 2. * self.map.value(rec.uv, rec.p) - 1.;
+        let n = n.x * tu + n.y * bitang + n.z * outward;
+        Perturbed::new(self.inner.as_ref(), rec, n.try_normalize().unwrap_or(outward))
+    }
+}
+
+impl Material for NormalMap {
+    fn flags(&self) -> LobeFlags {
+        self.inner.flags()
+    }
+
+    fn eval(&self, rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> Vec3A {
+        self.perturbed(rec).eval(wi, wo)
+    }
+
+    fn sample(&self, rec: &HitRecord, wi: Vec3A, uc: f32, u: Vec2) -> Option<BsdfSample> {
+        self.perturbed(rec).sample(wi, uc, u)
+    }
+
+    fn pdf(&self, rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> f32 {
+        self.perturbed(rec).pdf(wi, wo)
+    }
+
+    fn emitted(&self, uv: Vec2, p: Vec3A) -> Vec3A {
+        self.inner.emitted(uv, p)
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.inner.is_emissive()
+    }
+
+    fn is_cut_out(&self, r: &Ray, uv: Vec2, p: Vec3A) -> bool {
+        self.inner.is_cut_out(r, uv, p)
+    }
+
+    fn interior(&self) -> Option<Arc<Medium>> {
+        self.inner.interior()
+    }
+}
+
+/// Step of the finite differences in `BumpMap`, in uv for textures without an image
+/// and in world units per unit of hit distance.
+const BUMP_DELTA: f32 = 5e-4;
+
+/// Bump map over `inner`, tilting the normal by the gradient of the first channel of `height`.
+/// The gradient is taken along the directions `u` and `v` grow in, in `uv` and in `p` separately,
+/// so it works for image and solid textures alike with `scale` setting the height of the bumps in world units.
+/// Steps in `uv` are a texel of an image, so they always reach the neighbouring ones, and steps in `p`
+/// grow with the hit distance, so distant bumps don't turn into noise.
+pub struct BumpMap {
+    pub inner: Arc<dyn Material>,
+    pub height: Arc<dyn Texture>,
+    pub scale: f32,
+}
+
+impl BumpMap {
+    fn perturbed<'a>(&'a self, rec: &'a HitRecord) -> Perturbed<'a> {
+        let outward = if rec.front_face { rec.norm } else { -rec.norm };
+        let (tu, tv, len) = uv_axes(rec, outward);
+        let h = |uv: Vec2, p: Vec3A| self.height.value(uv, p).x * self.scale;
+        let h0 = h(rec.uv, rec.p);
+        let duv = self.height.resolution().map_or(Vec2::splat(BUMP_DELTA), |res| res.as_vec2().recip());
+        // not so small that `p` stops moving in f32
+        let dp = (BUMP_DELTA * rec.t).max(1e-5 * rec.p.abs().max_element());
+        // slopes per world unit along `tu` and `tv`
+        let dh_du = (h(rec.uv + vec2(duv.x, 0.), rec.p) - h0) / (duv.x * len.x) + (h(rec.uv, rec.p + dp * tu) - h0) / dp;
+        let dh_dv = (h(rec.uv + vec2(0., duv.y), rec.p) - h0) / (duv.y * len.y) + (h(rec.uv, rec.p + dp * tv) - h0) / dp;
+        // the gradient with those slopes, `tu` and `tv` may be skewed
+        let c = tu.dot(tv);
+        let grad = ((dh_du - c * dh_dv) * tu + (dh_dv - c * dh_du) * tv) / (1. - c * c);
+        let n = (outward - grad).normalize();
+        Perturbed::new(self.inner.as_ref(), rec, n)
+    }
+}
+
+impl Material for BumpMap {
+    fn flags(&self) -> LobeFlags {
+        self.inner.flags()
+    }
+
+    fn eval(&self, rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> Vec3A {
+        self.perturbed(rec).eval(wi, wo)
+    }
+
+    fn sample(&self, rec: &HitRecord, wi: Vec3A, uc: f32, u: Vec2) -> Option<BsdfSample> {
+        self.perturbed(rec).sample(wi, uc, u)
+    }
+
+    fn pdf(&self, rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> f32 {
+        self.perturbed(rec).pdf(wi, wo)
+    }
+
+    fn emitted(&self, uv: Vec2, p: Vec3A) -> Vec3A {
+        self.inner.emitted(uv, p)
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.inner.is_emissive()
+    }
+
+    fn is_cut_out(&self, r: &Ray, uv: Vec2, p: Vec3A) -> bool {
+        self.inner.is_cut_out(r, uv, p)
+    }
+
+    fn interior(&self) -> Option<Arc<Medium>> {
+        self.inner.interior()
+    }
+}
@@ -0,0 +1,320 @@
+use std::fmt;
+
+use glam::*;
+use rand::Rng;
+
+use crate::lib::RNG;
+use crate::math::{Ray, AABB};
+
+/// Cost of visiting a node, relative to intersecting one primitive.
+pub const SAH_TRAVERSAL_COST: f32 = 0.125;
+pub const SAH_INTERSECT_COST: f32 = 1.;
+
+#[derive(Clone, Copy, Debug)]
+pub enum BvhSplit {
+    /// Median of the primitives along a random axis, one primitive per leaf.
+    RandomMedian,
+    /// Binned surface area heuristic over primitive centroids.
+    Sah { bins: usize },
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BvhOptions {
+    pub split: BvhSplit,
+    pub max_leaf_size: usize,
+}
+
+impl Default for BvhOptions {
+    fn default() -> Self {
+        Self {
+            split: BvhSplit::Sah { bins: 16 },
+            max_leaf_size: 4,
+        }
+    }
+}
+
+#[derive(Default, Clone, Copy, Debug)]
+pub struct BvhStats {
+    pub nodes: usize,
+    pub leaves: usize,
+    pub max_depth: usize,
+    /// Expected cost of a random ray through the tree, see `SAH_TRAVERSAL_COST`.
+    pub sah_cost: f32,
+    pub build_time: f32,
+}
+
+impl BvhStats {
+    pub fn add_node(&mut self, aabb: &AABB, depth: usize) {
+        self.nodes += 1;
+        self.max_depth = self.max_depth.max(depth);
+        self.sah_cost += SAH_TRAVERSAL_COST * aabb.surface_area();
+    }
+
+    pub fn add_leaf(&mut self, aabb: &AABB, prims: usize, depth: usize) {
+        self.leaves += 1;
+        self.max_depth = self.max_depth.max(depth);
+        self.sah_cost += SAH_INTERSECT_COST * prims as f32 * aabb.surface_area();
+    }
+
+    /// Normalizes the area weighted cost by the root area.
+    pub fn finish(&mut self, root: &AABB, build_time: f32) {
+        let area = root.surface_area();
+        if area > 0. {
+            self.sah_cost /= area;
+        }
+        self.build_time = build_time;
+    }
+}
+
+impl fmt::Display for BvhStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "nodes: {}, leaves: {}, depth: {}, sah cost: {:.2}, build {:.3} s",
+            self.nodes, self.leaves, self.max_depth, self.sah_cost, self.build_time)
+    }
+}
+
+/// A primitive as seen by the builders, `index` points back into the caller's primitive list.
+#[derive(Clone, Copy, Debug)]
+pub struct BuildPrim {
+    pub aabb: AABB,
+    pub centroid: Vec3A,
+    pub index: usize,
+}
+
+impl BuildPrim {
+    pub fn new(index: usize, aabb: AABB) -> Self {
+        Self { aabb, centroid: aabb.centroid(), index }
+    }
+}
+
+pub fn prims_bounds(prims: &[BuildPrim]) -> AABB {
+    prims.iter().fold(AABB::empty(), |b, p| b.surround(p.aabb))
+}
+
+fn centroid_bounds(prims: &[BuildPrim]) -> AABB {
+    prims.iter().fold(AABB::empty(), |b, p| b.grow(p.centroid))
+}
+
+/// Sorts `prims` along `axis` and returns the middle.
+pub fn median_split(prims: &mut [BuildPrim], axis: usize) -> usize {
+    prims.sort_by(|a, b| a.aabb.min[axis].total_cmp(&b.aabb.min[axis]));
+    prims.len() / 2
+}
+
+#[derive(Clone, Copy)]
+struct Bin {
+    aabb: AABB,
+    count: usize,
+}
+
+/// Partitions `prims` in place according to `options`.
+/// Returns the index of the first primitive of the right half and the split axis,
+/// or `None` when `prims` should become a leaf.
+pub fn split_prims(prims: &mut [BuildPrim], bounds: &AABB, options: BvhOptions) -> Option<(usize, usize)> {
+    match options.split {
+        BvhSplit::RandomMedian => {
+            if prims.len() == 1 {
+                return None;
+            }
+            let axis: usize = RNG.with(|rng| {
+                rng.borrow_mut().gen_range(0..3)
+            });
+            Some((median_split(prims, axis), axis))
+        },
+        BvhSplit::Sah { bins } => sah_split(prims, bounds, bins, options.max_leaf_size),
+    }
+}
+
+/// Binned SAH split, Ingo Wald, "On fast Construction of SAH-based Bounding Volume Hierarchies".
+/// Partitions `prims` in place and returns the index of the first primitive of the right half
+/// and the split axis, or `None` when a leaf is cheaper and `prims` fits into one.
+pub fn sah_split(prims: &mut [BuildPrim], bounds: &AABB, bins: usize, max_leaf_size: usize) -> Option<(usize, usize)> {
+    let n = prims.len();
+    if n <= 1 {
+        return None;
+    }
+    let cb = centroid_bounds(prims);
+    let axis = cb.largest_axis();
+    let extent = cb.max[axis] - cb.min[axis];
+    if extent <= 0. {
+        // all centroids coincide, no plane separates them
+        return if n <= max_leaf_size { None } else { Some((n / 2, axis)) };
+    }
+
+    let bins = bins.max(2);
+    let bin_of = |p: &BuildPrim| {
+        let b = ((p.centroid[axis] - cb.min[axis]) / extent * bins as f32) as usize;
+        b.min(bins - 1)
+    };
+    let mut bin = vec![Bin { aabb: AABB::empty(), count: 0 }; bins];
+    for p in prims.iter() {
+        let b = &mut bin[bin_of(p)];
+        b.aabb = b.aabb.surround(p.aabb);
+        b.count += 1;
+    }
+
+    // sweep from the right to get the areas of every right half
+    let mut right_area = vec![0.; bins];
+    let mut right_count = vec![0; bins];
+    let mut acc = Bin { aabb: AABB::empty(), count: 0 };
+    for i in (1..bins).rev() {
+        acc.aabb = acc.aabb.surround(bin[i].aabb);
+        acc.count += bin[i].count;
+        right_area[i] = acc.aabb.surface_area();
+        right_count[i] = acc.count;
+    }
+
+    let mut best = (f32::INFINITY, 0);
+    let mut acc = Bin { aabb: AABB::empty(), count: 0 };
+    for i in 0..bins - 1 {
+        acc.aabb = acc.aabb.surround(bin[i].aabb);
+        acc.count += bin[i].count;
+        if acc.count == 0 || right_count[i + 1] == 0 {
+            continue;
+        }
+        let cost = acc.aabb.surface_area() * acc.count as f32 + right_area[i + 1] * right_count[i + 1] as f32;
+        if cost < best.0 {
+            best = (cost, i);
+        }
+    }
+
+    let area = bounds.surface_area().max(f32::MIN_POSITIVE);
+    let split_cost = SAH_TRAVERSAL_COST + SAH_INTERSECT_COST * best.0 / area;
+    let leaf_cost = SAH_INTERSECT_COST * n as f32;
+    if n <= max_leaf_size && leaf_cost <= split_cost {
+        return None;
+    }
+    if best.0 == f32::INFINITY {
+        return Some((median_split(prims, axis), axis));
+    }
+
+    let mut mid = 0;
+    for i in 0..n {
+        if bin_of(&prims[i]) <= best.1 {
+            prims.swap(i, mid);
+            mid += 1;
+        }
+    }
+    Some((mid, axis))
+}
+
+/// The traversal stack is fixed size, deeper subtrees are collapsed into leaves.
+const LINEAR_BVH_MAX_DEPTH: usize = 64;
+
+/// Node of a `LinearBvh`, stored in depth first order.
+/// Interior nodes have their first child right after them and the second one at `offset`,
+/// leaves hold `count` primitives starting at `offset` in `LinearBvh::prim_indices`.
+#[derive(Clone, Copy, Debug)]
+pub struct LinearBvhNode {
+    pub aabb: AABB,
+    pub offset: u32,
+    pub count: u32,
+    pub axis: u8,
+}
+
+/// Flattened BVH over primitive indices, the primitives themselves are owned by the caller.
+/// Matt Pharr, Wenzel Jakob, Greg Humphreys, "Physically Based Rendering", 4.3.4 Compact BVH For Traversal
+pub struct LinearBvh {
+    nodes: Vec<LinearBvhNode>,
+    prim_indices: Vec<u32>,
+}
+
+impl LinearBvh {
+    pub fn new(mut prims: Vec<BuildPrim>, options: BvhOptions) -> (Self, BvhStats) {
+        let timer = std::time::Instant::now();
+        let mut bvh = Self {
+            nodes: Vec::with_capacity(prims.len() * 2),
+            prim_indices: Vec::with_capacity(prims.len()),
+        };
+        let mut stats = BvhStats::default();
+        if !prims.is_empty() {
+            bvh.build(&mut prims, options, 0, &mut stats);
+        }
+        let root = bvh.bounds().unwrap_or_default();
+        stats.finish(&root, timer.elapsed().as_secs_f32());
+        (bvh, stats)
+    }
+
+    fn build(&mut self, prims: &mut [BuildPrim], options: BvhOptions, depth: usize, stats: &mut BvhStats) {
+        let aabb = prims_bounds(prims);
+        let split = if depth + 1 < LINEAR_BVH_MAX_DEPTH {
+            split_prims(prims, &aabb, options)
+        } else {
+            None
+        };
+
+        match split {
+            None => {
+                stats.add_leaf(&aabb, prims.len(), depth);
+                self.nodes.push(LinearBvhNode {
+                    aabb,
+                    offset: self.prim_indices.len() as u32,
+                    count: prims.len() as u32,
+                    axis: 0,
+                });
+                self.prim_indices.extend(prims.iter().map(|p| p.index as u32));
+            },
+            Some((mid, axis)) => {
+                stats.add_node(&aabb, depth);
+                let node_index = self.nodes.len();
+                self.nodes.push(LinearBvhNode { aabb, offset: 0, count: 0, axis: axis as u8 });
+                let (l, r) = prims.split_at_mut(mid);
+                self.build(l, options, depth + 1, stats);
+                self.nodes[node_index].offset = self.nodes.len() as u32;
+                self.build(r, options, depth + 1, stats);
+            },
+        }
+    }
+
+    pub fn bounds(&self) -> Option<AABB> {
+        self.nodes.first().map(|n| n.aabb)
+    }
+
+    /// Visits the leaves overlapping `r` front to back.
+    /// `hit_prim(index, t_max)` returns the distance of a hit closer than `t_max`, which then shrinks the ray.
+    pub fn traverse<F>(&self, r: &Ray, t_min: f32, mut t_max: f32, mut hit_prim: F) -> bool
+    where
+        F: FnMut(usize, f32) -> Option<f32>,
+    {
+        if self.nodes.is_empty() {
+            return false;
+        }
+        let inv_d = r.d.recip();
+        let dir_is_neg = [inv_d.x < 0., inv_d.y < 0., inv_d.z < 0.];
+        let mut hit = false;
+        let mut stack = [0usize; LINEAR_BVH_MAX_DEPTH];
+        let mut sp = 0;
+        let mut current = 0;
+        loop {
+            let node = &self.nodes[current];
+            if node.aabb.hit_inv(r.o, inv_d, t_min, t_max) {
+                if node.count > 0 {
+                    let start = node.offset as usize;
+                    for &prim in &self.prim_indices[start..start + node.count as usize] {
+                        if let Some(t) = hit_prim(prim as usize, t_max) {
+                            t_max = t;
+                            hit = true;
+                        }
+                    }
+                } else {
+                    // descend into the child on the ray origin's side first
+                    let (near, far) = if dir_is_neg[node.axis as usize] {
+                        (node.offset as usize, current + 1)
+                    } else {
+                        (current + 1, node.offset as usize)
+                    };
+                    stack[sp] = far;
+                    sp += 1;
+                    current = near;
+                    continue;
+                }
+            }
+            if sp == 0 {
+                break;
+            }
+            sp -= 1;
+            current = stack[sp];
+        }
+        hit
+    }
+}
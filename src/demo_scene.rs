@@ -1,9 +1,18 @@
 use std::sync::Arc;
 
+use crate::bump::*;
+use crate::subsurface::Subsurface;
+use crate::bvh::{BvhOptions, BvhSplit};
 use crate::camera::Camera;
 use crate::hitable::*;
+use crate::instance::*;
+use crate::light::*;
 use crate::material::*;
 use crate::pbr::*;
+use crate::phase::*;
+use crate::volume::*;
+use crate::sparse_grid::SparseGrid;
+use crate::sky::*;
 use crate::texture::*;
 use crate::math::*;
 use once_cell::sync::OnceCell;
@@ -18,6 +27,12 @@ use crate::RNG;
 
 pub static ENV_TEX: OnceCell<ImageTex> = OnceCell::new();
 
+// switch to `BvhSplit::RandomMedian` to compare against the old builder
+const BVH_OPTIONS: BvhOptions = BvhOptions {
+    split: BvhSplit::Sah { bins: 16 },
+    max_leaf_size: 4,
+};
+
 #[allow(dead_code)]
 fn tex_sky_color(d: Vec3A) -> Vec3A {
     let uv = Sphere::get_uv(d);
@@ -34,7 +49,7 @@ fn black_sky(_d: Vec3A) -> Vec3A {
     Vec3A::ZERO
 }
 
-pub fn sphere_scene(aspect_ratio: f32) -> (Vec<Arc<dyn Hitable>>, Camera) {
+pub fn sphere_scene(aspect_ratio: f32) -> (Vec<Arc<dyn Hitable>>, LightList, Camera) {
     SKY_COLOR.set(sky_color).unwrap();
 
     // let checker = Arc::new(CheckerTex::new(vec3a(0.2, 0.3, 0.1), vec3a(0.9, 0.9, 0.9)));
@@ -43,7 +58,7 @@ pub fn sphere_scene(aspect_ratio: f32) -> (Vec<Arc<dyn Hitable>>, Camera) {
 
     let material_ground = Arc::new(Diffuse { albedo: perlin});
     let material_1 = Arc::new(Emission { emit: earth_map});
-    let material_2 = Arc::new(Dielectric {ior : 1.5});
+    let material_2 = Arc::new(Dielectric {ior : 1.5, film: None, medium: None});
     let material_3 = Arc::new(Metal { albedo: vec3a(0.8, 0.6, 0.2), fuzz: 0.});
 
     let mut world: HitableList = vec![
@@ -82,10 +97,11 @@ pub fn sphere_scene(aspect_ratio: f32) -> (Vec<Arc<dyn Hitable>>, Camera) {
         20.,
         aspect_ratio,
     );
-    (build_bvh(&mut world), cam)
+    let (world, lights) = build_bvh(world);
+    (world, lights, cam)
 }
 
-pub fn simple_light_scene(aspect_ratio: f32) -> (Vec<Arc<dyn Hitable>>, Camera) {
+pub fn simple_light_scene(aspect_ratio: f32) -> (Vec<Arc<dyn Hitable>>, LightList, Camera) {
     SKY_COLOR.set(black_sky).unwrap();
 
     let perlin = Arc::new(PerlinTex::new(4.));
@@ -93,7 +109,7 @@ pub fn simple_light_scene(aspect_ratio: f32) -> (Vec<Arc<dyn Hitable>>, Camera)
     let mat_perlin = Arc::new(Diffuse { albedo: perlin});
     let material_1 = Arc::new(Emission { emit: Arc::new(ConstantTex{ col: vec3a(4., 4., 4.)})});
 
-    let mut world: HitableList = vec![
+    let world: HitableList = vec![
         Arc::new(Sphere {c: vec3a( 1.0, -1000., -1.0), r: 1000.0, mat: mat_perlin.clone(), name: "Ground".to_string()}),
         Arc::new(Sphere {c: vec3a( 0.0, 2.0, 0.0), r: 2., mat: mat_perlin.clone(), name: "Sphere_1".to_string()}),
         Arc::new(Sphere {c: vec3a( 0.0, 6.5, 0.0), r: 2., mat: material_1.clone(), name: "Sphere_2".to_string()}),
@@ -106,10 +122,161 @@ pub fn simple_light_scene(aspect_ratio: f32) -> (Vec<Arc<dyn Hitable>>, Camera)
         20.,
         aspect_ratio,
     );
-    (build_bvh(&mut world), cam)
+    let (world, lights) = build_bvh(world);
+    (world, lights, cam)
+}
+
+/// `simple_light_scene` in a forward scattering haze, which glows around the lights.
+pub fn fog_scene(aspect_ratio: f32) -> (Vec<Arc<dyn Hitable>>, LightList, Camera) {
+    SKY_COLOR.set(black_sky).unwrap();
+
+    let perlin = Arc::new(PerlinTex::new(4.));
+
+    let mat_perlin = Arc::new(Diffuse { albedo: perlin});
+    let material_1 = Arc::new(Emission { emit: Arc::new(ConstantTex{ col: vec3a(4., 4., 4.)})});
+    let haze = Arc::new(Sphere {c: Vec3A::ZERO, r: 60., mat: mat_perlin.clone(), name: "Haze".to_string()});
+
+    let world: HitableList = vec![
+        Arc::new(Sphere {c: vec3a( 1.0, -1000., -1.0), r: 1000.0, mat: mat_perlin.clone(), name: "Ground".to_string()}),
+        Arc::new(Sphere {c: vec3a( 0.0, 2.0, 0.0), r: 2., mat: mat_perlin.clone(), name: "Sphere_1".to_string()}),
+        Arc::new(Sphere {c: vec3a( 0.0, 6.5, 0.0), r: 2., mat: material_1.clone(), name: "Sphere_2".to_string()}),
+        Arc::new(XYRect {min: vec3a(3., 1., -2.), max: vec3a(5., 3., -2.), mat: material_1.clone()}),
+        Arc::new(ConstantMedium::with_phase(haze, 0.02, Arc::new(ConstantTex{ col: Vec3A::splat(0.9) }),
+            Arc::new(HenyeyGreenstein { g: 0.7 }))),
+    ];
+    let cam = Camera::new(
+        vec3a(26., 3., 6.),
+        vec3a(0., 0., 0.),
+        vec3a(0., 1., 0.),
+        20.,
+        aspect_ratio,
+    );
+    let (world, lights) = build_bvh(world);
+    (world, lights, cam)
+}
+
+/// A noise cloud over the ground, a glowing plume of smoke in a voxel grid and a smoke ring in a sparse one.
+pub fn volume_scene(aspect_ratio: f32) -> (Vec<Arc<dyn Hitable>>, LightList, Camera) {
+    SKY_COLOR.set(sky_color).unwrap();
+
+    let ground = Arc::new(Diffuse { albedo: Arc::new(ConstantTex{ col: vec3a(0.48, 0.83, 0.53)})});
+    let boundary = Arc::new(Diffuse { albedo: Arc::new(ConstantTex{ col: Vec3A::ONE})});
+
+    let cloud = Arc::new(Sphere {c: vec3a(-2.5, 3., 0.), r: 2.5, mat: boundary.clone(), name: "Cloud".to_string()});
+    let cloud = HeterogeneousMedium::new(cloud, Arc::new(NoiseDensity::new(0.6, 6.)),
+        Arc::new(ConstantTex{ col: Vec3A::splat(0.95) }), None,
+        Arc::new(DoubleHenyeyGreenstein { g1: 0.8, g2: -0.3, w: 0.8 }));
+
+    // a column thinning out upwards, hot and absorbing near the bottom
+    let res = [32, 64, 32];
+    let mut data = Vec::with_capacity(res[0] * res[1] * res[2]);
+    for z in 0..res[2] {
+        for y in 0..res[1] {
+            for x in 0..res[0] {
+                let h = y as f32 / res[1] as f32;
+                let r = vec2(x as f32 / res[0] as f32 - 0.5, z as f32 / res[2] as f32 - 0.5).length();
+                let width = 0.15 + 0.3 * h;
+                data.push(((1. - r / width).max(0.) * (1. - h)).powi(2));
+            }
+        }
+    }
+    let (min, max) = (vec3a(1., 0., -1.5), vec3a(4., 6., 1.5));
+    let plume = Arc::new(GBox::new(min, max, boundary.clone()));
+    let plume = HeterogeneousMedium::new(plume, Arc::new(VoxelGrid::new(min, max, res, data, 8.)),
+        Arc::new(ConstantTex{ col: vec3a(0.5, 0.4, 0.3) }), Some(Arc::new(ConstantTex{ col: vec3a(4., 1.2, 0.2) })),
+        Arc::new(HenyeyGreenstein { g: 0.3 }));
+
+    // a smoke ring only stored where it is, bounded as tightly
+    let mut ring = Vec::new();
+    for z in -4..4 {
+        for y in -40..40 {
+            for x in -40..40 {
+                let d = vec2((vec2(x as f32, y as f32).length() - 28.) / 8., z as f32 / 4.).length();
+                if d < 1. {
+                    ring.push((ivec3(x, y, z), 1. - d));
+                }
+            }
+        }
+    }
+    let ring = Arc::new(SparseGrid::from_voxels(vec3a(-2., 7., -3.), 0.05, ring, 12.));
+    let ring_boundary = Arc::new(GBox::new(ring.bounds.min, ring.bounds.max, boundary));
+    let ring = HeterogeneousMedium::new(ring_boundary, ring, Arc::new(ConstantTex{ col: Vec3A::splat(0.8) }), None,
+        Arc::new(HenyeyGreenstein { g: 0.5 }));
+
+    let world: HitableList = vec![
+        Arc::new(Sphere {c: vec3a(0.0, -1000., 0.0), r: 1000.0, mat: ground, name: "Ground".to_string()}),
+        Arc::new(cloud),
+        Arc::new(plume),
+        Arc::new(ring),
+    ];
+    let cam = Camera::new(
+        vec3a(0., 4., 20.),
+        vec3a(0., 4., 0.),
+        vec3a(0., 1., 0.),
+        40.,
+        aspect_ratio,
+    );
+    let (world, lights) = build_bvh(world);
+    (world, lights, cam)
+}
+
+/// Glass spheres holding ink and milk in a scene filled with thin fog.
+pub fn medium_scene(aspect_ratio: f32) -> (Vec<Arc<dyn Hitable>>, LightList, Camera) {
+    SKY_COLOR.set(black_sky).unwrap();
+    let fog = Medium::new(Vec3A::splat(0.002), Vec3A::splat(0.01), Arc::new(HenyeyGreenstein { g: 0.6 }));
+    SCENE_MEDIUM.set(Arc::new(fog)).ok().unwrap();
+
+    let ground = Arc::new(Diffuse { albedo: Arc::new(CheckerTex::new(vec3a(0.2, 0.3, 0.1), vec3a(0.9, 0.9, 0.9)))});
+    let light = Arc::new(Emission { emit: Arc::new(ConstantTex{ col: vec3a(8., 8., 8.)})});
+    let ink = Medium::new(vec3a(1.5, 0.6, 0.1), Vec3A::ZERO, Arc::new(HenyeyGreenstein { g: 0. }));
+    let ink = Arc::new(Dielectric { ior: 1.33, film: None, medium: Some(Arc::new(ink)) });
+    let milk = Medium::new(vec3a(0.01, 0.02, 0.05), Vec3A::splat(3.), Arc::new(HenyeyGreenstein { g: 0.8 }));
+    let milk = Arc::new(Dielectric { ior: 1.35, film: None, medium: Some(Arc::new(milk)) });
+
+    let world: HitableList = vec![
+        Arc::new(Sphere {c: vec3a(0.0, -1000., 0.0), r: 1000.0, mat: ground, name: "Ground".to_string()}),
+        Arc::new(Sphere {c: vec3a(-2.2, 1.5, 0.0), r: 1.5, mat: ink, name: "Ink".to_string()}),
+        Arc::new(Sphere {c: vec3a(2.2, 1.5, 0.0), r: 1.5, mat: milk, name: "Milk".to_string()}),
+        Arc::new(Sphere {c: vec3a(0.0, 7., -3.0), r: 1., mat: light, name: "Light".to_string()}),
+    ];
+    let cam = Camera::new(
+        vec3a(0., 3., 14.),
+        vec3a(0., 1.5, 0.),
+        vec3a(0., 1., 0.),
+        35.,
+        aspect_ratio,
+    );
+    let (world, lights) = build_bvh(world);
+    (world, lights, cam)
+}
+
+pub fn sun_sky_scene(aspect_ratio: f32) -> (Vec<Arc<dyn Hitable>>, LightList, Camera) {
+    // late afternoon sun from the right, behind the camera
+    set_sun_sky(SunSky::new(25., 60., 3., vec3a(0.3, 0.3, 0.3), 0.05));
+
+    let ground = Arc::new(Diffuse { albedo: Arc::new(CheckerTex::new(vec3a(0.3, 0.3, 0.3), vec3a(0.8, 0.8, 0.8)))});
+    let white = Arc::new(Diffuse { albedo: Arc::new(ConstantTex{ col: vec3a(0.8, 0.8, 0.8)})});
+    let glass = Arc::new(Dielectric { ior: 1.5, film: None, medium: None });
+    let gold = Arc::new(Metal { albedo: vec3a(1.0, 0.78, 0.34), fuzz: 0.1 });
+
+    let world: HitableList = vec![
+        Arc::new(Sphere {c: vec3a(0.0, -1000., 0.0), r: 1000.0, mat: ground, name: "Ground".to_string()}),
+        Arc::new(Sphere {c: vec3a(-2.2, 1., 0.0), r: 1., mat: white, name: "White".to_string()}),
+        Arc::new(Sphere {c: vec3a(0.0, 1., 0.0), r: 1., mat: glass, name: "Glass".to_string()}),
+        Arc::new(Sphere {c: vec3a(2.2, 1., 0.0), r: 1., mat: gold, name: "Gold".to_string()}),
+    ];
+    let cam = Camera::new(
+        vec3a(0., 2., 10.),
+        vec3a(0., 1.5, 0.),
+        vec3a(0., 1., 0.),
+        40.,
+        aspect_ratio,
+    );
+    let (world, lights) = build_bvh(world);
+    (world, lights, cam)
 }
 
-pub fn cornell_box(aspect_ratio: f32) -> (Vec<Arc<dyn Hitable>>, Camera) {
+pub fn cornell_box(aspect_ratio: f32) -> (Vec<Arc<dyn Hitable>>, LightList, Camera) {
     SKY_COLOR.set(black_sky).unwrap();
 
     let red = Arc::new(Diffuse { albedo: Arc::new(ConstantTex{ col: vec3a(0.65, 0.05, 0.05)})});
@@ -118,16 +285,16 @@ pub fn cornell_box(aspect_ratio: f32) -> (Vec<Arc<dyn Hitable>>, Camera) {
     let light = Arc::new(Emission { emit: Arc::new(ConstantTex{ col: vec3a(7., 7., 7.)})});
 
     let box_1 = Arc::new(GBox::new(Vec3A::ZERO, vec3a(165., 330., 165.), white.clone()));
-    let box_1 = Arc::new(RotateY::new(box_1, 15.));
-    let box_1 = Arc::new(Translate {offset: vec3a(265., 0., 295.), ptr: box_1});
+    let box_1 = Arc::new(Transform::new(box_1, Affine3A::from_rotation_translation(
+        Quat::from_rotation_y(15f32.to_radians()), vec3(265., 0., 295.))));
     let mediun_1 = Arc::new(ConstantMedium::new(box_1.clone(), 0.01, Arc::new(ConstantTex{ col: Vec3A::ZERO })));
 
     let box_2 = Arc::new(GBox::new(Vec3A::ZERO, vec3a(165., 165., 165.), white.clone()));
-    let box_2 = Arc::new(RotateY::new(box_2, -18.));
-    let box_2 = Arc::new(Translate {offset: vec3a(130., 0., 65.), ptr: box_2});
+    let box_2 = Arc::new(Transform::new(box_2, Affine3A::from_rotation_translation(
+        Quat::from_rotation_y(-18f32.to_radians()), vec3(130., 0., 65.))));
     let mediun_2 = Arc::new(ConstantMedium::new(box_2.clone(), 0.01, Arc::new(ConstantTex{ col: Vec3A::ONE })));
 
-    let mut world: HitableList = vec![
+    let world: HitableList = vec![
         Arc::new(XZRect {min: vec3a(113., 554., 127.), max: vec3a(443., 554., 432.), mat: light.clone()}),
         Arc::new(XYRect {min: vec3a(0., 0., 555.), max: vec3a(555., 555., 555.), mat: white.clone()}),
         Arc::new(XZRect {min: vec3a(0., 0., 0.), max: vec3a(555., 0., 555.), mat: white.clone()}),
@@ -144,17 +311,18 @@ pub fn cornell_box(aspect_ratio: f32) -> (Vec<Arc<dyn Hitable>>, Camera) {
         40.,
         aspect_ratio,
     );
-    (build_bvh(&mut world), cam)
+    let (world, lights) = build_bvh(world);
+    (world, lights, cam)
 }
 
-pub fn final_scene(aspect_ratio: f32) -> (Vec<Arc<dyn Hitable>>, Camera) {
+pub fn final_scene(aspect_ratio: f32) -> (Vec<Arc<dyn Hitable>>, LightList, Camera) {
     SKY_COLOR.set(black_sky).unwrap();
 
     let ground = Arc::new(Diffuse { albedo: Arc::new(ConstantTex{ col: vec3a(0.48, 0.83, 0.53)})});
     let white = Arc::new(Diffuse { albedo: Arc::new(ConstantTex{ col: vec3a(0.73, 0.73, 0.73)})});
     let brown = Arc::new(BurleyDiffuse { albedo: Arc::new(ConstantTex{ col: vec3a(0.7, 0.3, 0.1)}), roughness: 0.9});
     let light = Arc::new(Emission { emit: Arc::new(ConstantTex{ col: vec3a(7., 7., 7.)})});
-    let dielectric = Arc::new(Dielectric {ior : 1.5});
+    let dielectric = Arc::new(Dielectric {ior : 1.5, film: None, medium: None});
     let metal = Arc::new(Metal { albedo: vec3a(0.8, 0.8, 0.9), fuzz: 1.});
 
     let earth_map = Arc::new(ImageTex::new("res/earthmap.jpg".into()));
@@ -176,9 +344,9 @@ pub fn final_scene(aspect_ratio: f32) -> (Vec<Arc<dyn Hitable>>, Camera) {
     for _ in 0..1000 {
         spheres.push(Arc::new(Sphere {c: vec3a_random_range(0., 165.), r: 10.0, mat: white.clone(), name: "Ground".to_string()}));
     }
-    let spheres = Arc::new(BvhNode::new(&mut spheres, 0, 1000));
-    let spheres = Arc::new(RotateY::new(spheres, 15.));
-    let spheres = Arc::new(Translate {offset: vec3a(-100., 270., 395.), ptr: spheres});
+    let spheres = Arc::new(flat_bvh(spheres, "spheres"));
+    let spheres = Arc::new(Transform::new(spheres, Affine3A::from_rotation_translation(
+        Quat::from_rotation_y(15f32.to_radians()), vec3(-100., 270., 395.))));
 
     let mut boxes: HitableList = Vec::new();
     for i in 0..20 {
@@ -196,9 +364,9 @@ pub fn final_scene(aspect_ratio: f32) -> (Vec<Arc<dyn Hitable>>, Camera) {
             boxes.push(Arc::new(GBox::new(min, max, ground.clone())));
         }
     }
-    let boxes = Arc::new(BvhNode::new(&mut boxes, 0, 20 * 20));
+    let boxes = Arc::new(flat_bvh(boxes, "boxes"));
 
-    let mut world: HitableList = vec![
+    let world: HitableList = vec![
         Arc::new(XZRect {min: vec3a(123., 544., 147.), max: vec3a(423., 554., 412.), mat: light.clone()}),
         spheres,
         earth,
@@ -217,19 +385,154 @@ pub fn final_scene(aspect_ratio: f32) -> (Vec<Arc<dyn Hitable>>, Camera) {
         40.,
         aspect_ratio,
     );
-    (build_bvh(&mut world), cam)
+    let (world, lights) = build_bvh(world);
+    (world, lights, cam)
+}
+
+pub fn instancing_scene(aspect_ratio: f32) -> (Vec<Arc<dyn Hitable>>, LightList, Camera) {
+    SKY_COLOR.set(sky_color).unwrap();
+
+    let mut rng = SmallRng::seed_from_u64(95);
+
+    let ground = Arc::new(Diffuse { albedo: Arc::new(ConstantTex{ col: vec3a(0.48, 0.83, 0.53)})});
+    let stone = Arc::new(Diffuse { albedo: Arc::new(ConstantTex{ col: vec3a(0.5, 0.45, 0.4)})});
+    let moss = Arc::new(Diffuse { albedo: Arc::new(ConstantTex{ col: vec3a(0.2, 0.4, 0.1)})});
+
+    // one rock made of a few blobs, stored once and placed thousands of times
+    let mut blobs: HitableList = Vec::new();
+    for i in 0..12 {
+        let mat: Arc<dyn Material> = if i % 4 == 0 { moss.clone() } else { stone.clone() };
+        let c = vec3a(rng.gen_range(-0.6..0.6), rng.gen_range(0.0..0.5), rng.gen_range(-0.6..0.6));
+        blobs.push(Arc::new(Sphere {c, r: rng.gen_range(0.3..0.6), mat, name: format!("Blob {}", i)}));
+    }
+    let rock: Arc<dyn Hitable> = Arc::new(flat_bvh(blobs, "rock"));
+
+    let mut instances = Vec::new();
+    for a in -50..50 {
+        for b in -50..50 {
+            let scale = vec3(rng.gen_range(0.5..1.5), rng.gen_range(0.3..1.0), rng.gen_range(0.5..1.5));
+            let rotation = Quat::from_rotation_y(rng.gen_range(0.0..std::f32::consts::TAU));
+            let translation = vec3(a as f32 * 4. + rng.gen_range(-1.0..1.0), 0., b as f32 * 4. + rng.gen_range(-1.0..1.0));
+            let xform = Affine3A::from_scale_rotation_translation(scale, rotation, translation);
+            instances.push(Instance::new(rock.clone(), xform));
+        }
+    }
+    let (rocks, stats) = InstanceBvh::with_options(instances, BVH_OPTIONS);
+    eprintln!("bvh instances: {}", stats);
+
+    let world: HitableList = vec![
+        Arc::new(Sphere {c: vec3a(0.0, -1000., 0.0), r: 1000.0, mat: ground, name: "Ground".to_string()}),
+        Arc::new(rocks),
+    ];
+    let cam = Camera::new(
+        vec3a(30., 12., 40.),
+        vec3a(0., 0., 0.),
+        vec3a(0., 1., 0.),
+        40.,
+        aspect_ratio,
+    );
+    let (world, lights) = build_bvh(world);
+    (world, lights, cam)
 }
 
-fn build_bvh(world: &mut Vec<Arc<dyn Hitable>>) -> Vec<Arc<dyn Hitable>> {
-    let world_len = world.len();
-    let bvh: Arc<dyn Hitable> = Arc::new(BvhNode::new(world, 0, world_len));
-    vec![bvh]
+/// A row of spheres on a checker floor to compare materials side by side.
+pub fn material_scene(aspect_ratio: f32) -> (Vec<Arc<dyn Hitable>>, LightList, Camera) {
+    SKY_COLOR.set(sky_color).unwrap();
+
+    let constant = |v: f32| -> Arc<dyn Texture> { Arc::new(ConstantTex{ col: Vec3A::splat(v)}) };
+    let red: Arc<dyn Texture> = Arc::new(ConstantTex{ col: vec3a(0.8, 0.1, 0.1)});
+    let gold: Arc<dyn Texture> = Arc::new(ConstantTex{ col: vec3a(1.0, 0.78, 0.34)});
+    let blue: Arc<dyn Texture> = Arc::new(ConstantTex{ col: vec3a(0.05, 0.15, 0.6)});
+    let ground = Arc::new(Diffuse { albedo: Arc::new(CheckerTex::new(vec3a(0.2, 0.3, 0.1), vec3a(0.9, 0.9, 0.9)))});
+    let light = Arc::new(Emission { emit: Arc::new(ConstantTex{ col: vec3a(10., 10., 10.)})});
+
+    let materials: Vec<(&str, Arc<dyn Material>)> = vec![
+        ("Plastic", Arc::new(DisneyPrincipled { roughness: constant(0.3), ..DisneyPrincipled::new(red.clone()) })),
+        ("Lacquer", Arc::new(DisneyPrincipled { clearcoat: constant(1.), ..DisneyPrincipled::new(red.clone()) })),
+        ("Velvet", Arc::new(DisneyPrincipled { roughness: constant(1.), sheen: constant(1.), ..DisneyPrincipled::new(red) })),
+        ("Gold", Arc::new(DisneyPrincipled {
+            metallic: constant(1.), roughness: constant(0.25), anisotropic: constant(0.8), ..DisneyPrincipled::new(gold)
+        })),
+        ("FrostedGlass", Arc::new(DisneyPrincipled {
+            spec_trans: constant(1.), roughness: constant(0.2), ..DisneyPrincipled::new(constant(1.))
+        })),
+        ("Copper", Arc::new(Conductor::copper(0.3))),
+        ("Chrome", Arc::new(Conductor::chrome(0.))),
+        ("SoapBubble", Arc::new(Dielectric { ior: 1., film: Some(ThinFilm {
+            ior: 1.33, thickness: 600., thickness_map: Some(Arc::new(PerlinTex::new(4.))),
+        }), medium: None })),
+        ("TemperedSteel", Arc::new(Conductor {
+            film: Some(ThinFilm { ior: 2.2, thickness: 250., thickness_map: None }), ..Conductor::chrome(0.15)
+        })),
+        ("PearlPaint", Arc::new(DisneyPrincipled {
+            metallic: constant(0.8), roughness: constant(0.3),
+            film: Some(ThinFilm { ior: 1.5, thickness: 400., thickness_map: Some(Arc::new(PerlinTex::new(2.))) }),
+            ..DisneyPrincipled::new(constant(0.9))
+        })),
+        ("GreenGlass", Arc::new(RoughDielectric { ior: 1.5, roughness: 0.1, film: None, absorption: vec3a(0.8, 0.1, 0.6) })),
+        ("CarPaint", Arc::new(Coated {
+            inner: Arc::new(DisneyPrincipled { metallic: constant(0.6), roughness: constant(0.4), ..DisneyPrincipled::new(blue) }),
+            ior: 1.5, roughness: 0.02, thickness: 0.1, absorption: Vec3A::ZERO,
+        })),
+        ("RustyCopper", Arc::new(MixMaterial {
+            a: Arc::new(Conductor::copper(0.2)),
+            b: Arc::new(Diffuse { albedo: Arc::new(ConstantTex{ col: vec3a(0.35, 0.12, 0.05)}) }),
+            weight: Arc::new(PerlinTex::new(4.)),
+        })),
+        ("GlowingPlastic", Arc::new(AddMaterial {
+            a: Arc::new(DisneyPrincipled { roughness: constant(0.2), ..DisneyPrincipled::new(constant(0.8)) }),
+            b: Arc::new(Emission { emit: Arc::new(ConstantTex{ col: vec3a(1.5, 0.6, 0.1)}) }),
+        })),
+        ("HammeredCopper", Arc::new(BumpMap {
+            inner: Arc::new(Conductor::copper(0.15)), height: Arc::new(PerlinTex::new(2.)), scale: 0.05,
+        })),
+        ("Lattice", Arc::new(AlphaMask {
+            inner: Arc::new(Diffuse { albedo: constant(0.8) }),
+            alpha: Arc::new(CheckerTex::new(Vec3A::ZERO, Vec3A::ONE)),
+            cutoff: Some(0.5),
+        })),
+    ];
+
+    let mut world: HitableList = vec![
+        Arc::new(Sphere {c: vec3a(0.0, -1000., 0.0), r: 1000.0, mat: ground, name: "Ground".to_string()}),
+        Arc::new(Sphere {c: vec3a(-4.0, 8.0, 6.0), r: 1.5, mat: light, name: "Light".to_string()}),
+    ];
+    // the last slot is the wax, a volume rather than a material
+    let n = materials.len() + 1;
+    let slot = |i: usize| vec3a((i as f32 - (n - 1) as f32 / 2.) * 2.2, 1., 0.);
+    for (i, (name, mat)) in materials.into_iter().enumerate() {
+        world.push(Arc::new(Sphere {c: slot(i), r: 1., mat, name: name.to_string()}));
+    }
+    let wax = Arc::new(Sphere {c: slot(n - 1), r: 1., mat: Arc::new(Diffuse { albedo: constant(1.) }), name: "Wax".to_string()});
+    world.push(Arc::new(Subsurface::new(wax, vec3a(0.9, 0.7, 0.4), vec3a(0.2, 0.1, 0.05), 1.45, 0.)));
+    let cam = Camera::new(
+        vec3a(0., 3., 34.),
+        vec3a(0., 1., 0.),
+        vec3a(0., 1., 0.),
+        30.,
+        aspect_ratio,
+    );
+    let (world, lights) = build_bvh(world);
+    (world, lights, cam)
 }
 
-pub fn test_sphere(aspect_ratio: f32) -> (Vec<Arc<dyn Hitable>>, Camera) {
+fn flat_bvh(objects: HitableList, name: &str) -> FlatBvh {
+    let (bvh, stats) = FlatBvh::with_options(objects, BVH_OPTIONS);
+    eprintln!("bvh {}: {}", name, stats);
+    bvh
+}
+
+/// The lights of `world` and a BVH over it.
+fn build_bvh(world: HitableList) -> (Vec<Arc<dyn Hitable>>, LightList) {
+    let lights = LightList::new(&world);
+    let bvh: Arc<dyn Hitable> = Arc::new(flat_bvh(world, "world"));
+    (vec![bvh], lights)
+}
+
+pub fn test_sphere(aspect_ratio: f32) -> (Vec<Arc<dyn Hitable>>, LightList, Camera) {
     SKY_COLOR.set(sky_color).unwrap();
     let ground = Arc::new(Lambert { albedo: Arc::new(ConstantTex{ col: vec3a(0.5, 0.5, 0.5)})});
-    let mut world: HitableList = vec![
+    let world: HitableList = vec![
         Arc::new(Sphere {c: vec3a( 0.0, -100.5, -1.0), r: 100., mat: ground.clone(), name: "Ground".to_string()}),
         Arc::new(Sphere {c: vec3a( 0.0, 0.0, -1.0), r: 0.5, mat: ground, name: "Test".to_string()}),
     ];
@@ -240,5 +543,6 @@ pub fn test_sphere(aspect_ratio: f32) -> (Vec<Arc<dyn Hitable>>, Camera) {
         90.,
         aspect_ratio,
     );
-    (world, cam)
+    let lights = LightList::new(&world);
+    (world, lights, cam)
 }
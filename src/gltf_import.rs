@@ -0,0 +1,342 @@
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+use glam::*;
+use gltf::camera::Projection;
+use gltf::image::Format;
+
+use crate::camera::Camera;
+use crate::hitable::*;
+use crate::bump::NormalMap;
+use crate::material::{AddMaterial, Emission, Material};
+use crate::math::AABB;
+use crate::mesh::TriangleMesh;
+use crate::pbr::{DisneyDiffuse, DisneyPrincipled};
+use crate::texture::*;
+
+#[derive(Debug)]
+pub enum GltfError {
+    Gltf(gltf::Error),
+    Unsupported(String),
+}
+
+impl fmt::Display for GltfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GltfError::Gltf(e) => write!(f, "{}", e),
+            GltfError::Unsupported(msg) => write!(f, "unsupported glTF content: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for GltfError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            GltfError::Gltf(e) => Some(e),
+            GltfError::Unsupported(_) => None,
+        }
+    }
+}
+
+impl From<gltf::Error> for GltfError {
+    fn from(e: gltf::Error) -> Self {
+        GltfError::Gltf(e)
+    }
+}
+
+/// Texture scaled by a constant factor, e.g. `baseColorTexture * baseColorFactor`.
+struct TintTex {
+    tex: Arc<dyn Texture>,
+    tint: Vec3A,
+}
+
+impl Texture for TintTex {
+    fn value(&self, uv: Vec2, p: Vec3A) -> Vec3A {
+        self.tex.value(uv, p) * self.tint
+    }
+
+    fn resolution(&self) -> Option<UVec2> {
+        self.tex.resolution()
+    }
+}
+
+/// Linear colors of a texture storing them sRGB encoded, as glTF does for base color and emission.
+struct SrgbTex {
+    tex: Arc<dyn Texture>,
+}
+
+impl Texture for SrgbTex {
+    fn value(&self, uv: Vec2, p: Vec3A) -> Vec3A {
+        let c = self.tex.value(uv, p);
+        let decode = |c: f32| if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) };
+        vec3a(decode(c.x), decode(c.y), decode(c.z))
+    }
+
+    fn resolution(&self) -> Option<UVec2> {
+        self.tex.resolution()
+    }
+}
+
+/// One channel of `tex` times `factor` in all three, e.g. the metalness in the blue channel of `metallicRoughnessTexture`.
+struct ChannelTex {
+    tex: Arc<dyn Texture>,
+    channel: usize,
+    factor: f32,
+}
+
+impl Texture for ChannelTex {
+    fn value(&self, uv: Vec2, p: Vec3A) -> Vec3A {
+        Vec3A::splat(self.tex.value(uv, p)[self.channel] * self.factor)
+    }
+
+    fn resolution(&self) -> Option<UVec2> {
+        self.tex.resolution()
+    }
+}
+
+/// Tangent space normal texture with x and y scaled by `scale`, glTF's `normalTexture.scale`.
+struct NormalScaleTex {
+    tex: Arc<dyn Texture>,
+    scale: f32,
+}
+
+impl Texture for NormalScaleTex {
+    fn value(&self, uv: Vec2, p: Vec3A) -> Vec3A {
+        let n = (2. * self.tex.value(uv, p) - 1.) * vec3a(self.scale, self.scale, 1.);
+        0.5 * (n + 1.)
+    }
+
+    fn resolution(&self) -> Option<UVec2> {
+        self.tex.resolution()
+    }
+}
+
+fn convert_image(data: &gltf::image::Data) -> Result<ImageTex, GltfError> {
+    let (channels, bytes) = match data.format {
+        Format::R8 => (1, 1),
+        Format::R8G8 => (2, 1),
+        Format::R8G8B8 => (3, 1),
+        Format::R8G8B8A8 => (4, 1),
+        Format::R16 => (1, 2),
+        Format::R16G16 => (2, 2),
+        Format::R16G16B16 => (3, 2),
+        Format::R16G16B16A16 => (4, 2),
+        Format::R32G32B32FLOAT => (3, 4),
+        Format::R32G32B32A32FLOAT => (4, 4),
+    };
+    let texel = |i: usize| -> f32 {
+        let b = &data.pixels[i * bytes..(i + 1) * bytes];
+        match bytes {
+            1 => b[0] as f32 / 255.,
+            2 => u16::from_le_bytes([b[0], b[1]]) as f32 / 65535.,
+            _ => f32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+        }
+    };
+    let count = (data.width * data.height) as usize;
+    let mut rgb = Vec::with_capacity(count * 3);
+    for i in 0..count {
+        for c in 0..3 {
+            // grey and grey-alpha images replicate the first channel
+            let c = if channels < 3 { 0 } else { c };
+            rgb.push(texel(i * channels + c));
+        }
+    }
+    ImageTex::from_rgb32f(data.width, data.height, rgb)
+        .ok_or_else(|| GltfError::Unsupported("image size does not match its pixels".into()))
+}
+
+/// The one texture coordinate set all textures of `mat` read, meshes only carry one.
+fn tex_coord_set(mat: &gltf::Material) -> Result<u32, GltfError> {
+    let pbr = mat.pbr_metallic_roughness();
+    let mut sets = [
+        pbr.base_color_texture().map(|info| info.tex_coord()),
+        pbr.metallic_roughness_texture().map(|info| info.tex_coord()),
+        mat.normal_texture().map(|normal| normal.tex_coord()),
+        mat.emissive_texture().map(|info| info.tex_coord()),
+    ].into_iter().flatten();
+    let set = sets.next().unwrap_or(0);
+    if sets.any(|s| s != set) {
+        let name = mat.name().unwrap_or("unnamed");
+        return Err(GltfError::Unsupported(format!("material {} mixes texture coordinate sets", name)));
+    }
+    Ok(set)
+}
+
+/// Maps the metallic-roughness model onto `DisneyPrincipled`, which blends to metal by `metallic`,
+/// rather than picking `DisneyMetal` or `DisneyDiffuse` by it, which loses everything in between.
+/// The normal texture goes on top as a `NormalMap` and the emission is added to the surface.
+fn convert_material(mat: &gltf::Material, images: &[Arc<ImageTex>]) -> Arc<dyn Material> {
+    let image = |tex: gltf::Texture| -> Arc<dyn Texture> { images[tex.source().index()].clone() };
+    let color = |tex: Option<gltf::Texture>, factor: Vec3A| -> Arc<dyn Texture> {
+        match tex {
+            Some(tex) => {
+                let tex = Arc::new(SrgbTex { tex: image(tex) });
+                if factor == Vec3A::ONE {
+                    tex
+                } else {
+                    Arc::new(TintTex { tex, tint: factor })
+                }
+            },
+            None => Arc::new(ConstantTex { col: factor }),
+        }
+    };
+
+    let pbr = mat.pbr_metallic_roughness();
+    let base_color = color(pbr.base_color_texture().map(|info| info.texture()), Vec4::from(pbr.base_color_factor()).truncate().into());
+    let (metallic, roughness): (Arc<dyn Texture>, Arc<dyn Texture>) = match pbr.metallic_roughness_texture() {
+        Some(info) => {
+            let tex = image(info.texture());
+            (
+                Arc::new(ChannelTex { tex: tex.clone(), channel: 2, factor: pbr.metallic_factor() }),
+                Arc::new(ChannelTex { tex, channel: 1, factor: pbr.roughness_factor() }),
+            )
+        },
+        None => (
+            Arc::new(ConstantTex { col: Vec3A::splat(pbr.metallic_factor()) }),
+            Arc::new(ConstantTex { col: Vec3A::splat(pbr.roughness_factor()) }),
+        ),
+    };
+    let mut surface: Arc<dyn Material> = Arc::new(DisneyPrincipled { metallic, roughness, ..DisneyPrincipled::new(base_color) });
+
+    if let Some(normal) = mat.normal_texture() {
+        let scale = normal.scale();
+        let map = image(normal.texture());
+        let map = if scale == 1. { map } else { Arc::new(NormalScaleTex { tex: map, scale }) };
+        surface = Arc::new(NormalMap { inner: surface, map });
+    }
+    let emissive = Vec3A::from(mat.emissive_factor());
+    if emissive.max_element() > 0. {
+        let emit = color(mat.emissive_texture().map(|info| info.texture()), emissive);
+        surface = Arc::new(AddMaterial { a: Arc::new(Emission { emit }), b: surface });
+    }
+    surface
+}
+
+struct Importer<'a> {
+    buffers: &'a [gltf::buffer::Data],
+    materials: Vec<Arc<dyn Material>>,
+    /// Texture coordinate set of each material, see `tex_coord_set`.
+    tex_coords: Vec<u32>,
+    default_mat: Arc<dyn Material>,
+    world: HitableList,
+    cam: Option<Camera>,
+    aspect_ratio: f32,
+}
+
+impl<'a> Importer<'a> {
+    fn visit(&mut self, node: gltf::Node, parent: Mat4) -> Result<(), GltfError> {
+        let xform = parent * Mat4::from_cols_array_2d(&node.transform().matrix());
+
+        if let (Some(camera), None) = (node.camera(), &self.cam) {
+            if let Projection::Perspective(p) = camera.projection() {
+                let lookfrom = Vec3A::from(xform.transform_point3(Vec3::ZERO));
+                let forward = Vec3A::from(xform.transform_vector3(-Vec3::Z));
+                let vup = Vec3A::from(xform.transform_vector3(Vec3::Y));
+                self.cam = Some(Camera::new(lookfrom, lookfrom + forward, vup, p.yfov().to_degrees(), self.aspect_ratio));
+            }
+        }
+
+        if let Some(mesh) = node.mesh() {
+            self.add_mesh(&mesh, xform)?;
+        }
+        for child in node.children() {
+            self.visit(child, xform)?;
+        }
+        Ok(())
+    }
+
+    /// Bakes `xform` into the vertices of every triangle primitive of `mesh`.
+    fn add_mesh(&mut self, mesh: &gltf::Mesh, xform: Mat4) -> Result<(), GltfError> {
+        let normal_xform = Mat3::from_mat4(xform).inverse().transpose();
+        let flip = xform.determinant() < 0.;
+        for prim in mesh.primitives() {
+            if prim.mode() != gltf::mesh::Mode::Triangles {
+                return Err(GltfError::Unsupported(format!("primitive mode {:?}", prim.mode())));
+            }
+            let reader = prim.reader(|buffer| Some(&self.buffers[buffer.index()]));
+            let positions: Vec<Vec3A> = match reader.read_positions() {
+                Some(iter) => iter.map(|p| Vec3A::from(xform.transform_point3(Vec3::from(p)))).collect(),
+                None => return Err(GltfError::Unsupported("primitive without positions".into())),
+            };
+            let normals: Vec<Vec3A> = reader.read_normals()
+                .map(|iter| iter.map(|n| Vec3A::from(normal_xform * Vec3::from(n)).normalize_or_zero()).collect())
+                .unwrap_or_default();
+            let set = prim.material().index().map_or(0, |i| self.tex_coords[i]);
+            // glTF puts the uv origin at the top left of the image
+            let uvs: Vec<Vec2> = reader.read_tex_coords(set)
+                .map(|tc| tc.into_f32().map(|[u, v]| vec2(u, 1. - v)).collect())
+                .unwrap_or_default();
+            let mut indices: Vec<[u32; 3]> = match reader.read_indices() {
+                Some(idx) => {
+                    let idx: Vec<u32> = idx.into_u32().collect();
+                    idx.chunks_exact(3).map(|c| [c[0], c[1], c[2]]).collect()
+                },
+                None => (0..positions.len() as u32 / 3).map(|i| [3 * i, 3 * i + 1, 3 * i + 2]).collect(),
+            };
+            if flip {
+                for tri in &mut indices {
+                    tri.swap(1, 2);
+                }
+            }
+            if indices.is_empty() {
+                continue;
+            }
+
+            let mat = match prim.material().index() {
+                Some(i) => self.materials[i].clone(),
+                None => self.default_mat.clone(),
+            };
+            let name = format!("{}/{}", mesh.name().unwrap_or("mesh"), prim.index());
+            self.world.push(Arc::new(TriangleMesh::new(positions, normals, uvs, indices, mat, name)));
+        }
+        Ok(())
+    }
+}
+
+/// Loads a `.gltf`/`.glb` scene.
+/// The first perspective camera of the scene is used, otherwise the camera frames the whole scene from +z.
+pub fn load_gltf<P: AsRef<Path>>(path: P, aspect_ratio: f32) -> Result<(HitableList, Camera), GltfError> {
+    let (doc, buffers, images) = gltf::import(path)?;
+
+    let images = images.iter()
+        .map(|img| convert_image(img).map(Arc::new))
+        .collect::<Result<Vec<_>, _>>()?;
+    let materials = doc.materials().map(|m| convert_material(&m, &images)).collect();
+    let tex_coords = doc.materials().map(|m| tex_coord_set(&m)).collect::<Result<Vec<_>, _>>()?;
+    let default_mat: Arc<dyn Material> = Arc::new(DisneyDiffuse {
+        albedo: Arc::new(ConstantTex { col: Vec3A::ONE }),
+        roughness: 1.,
+        subsurface: 0.,
+    });
+
+    let mut importer = Importer {
+        buffers: &buffers,
+        materials,
+        tex_coords,
+        default_mat,
+        world: Vec::new(),
+        cam: None,
+        aspect_ratio,
+    };
+    let scene = doc.default_scene()
+        .or_else(|| doc.scenes().next())
+        .ok_or_else(|| GltfError::Unsupported("file has no scene".into()))?;
+    for node in scene.nodes() {
+        importer.visit(node, Mat4::IDENTITY)?;
+    }
+
+    let Importer { world, cam, .. } = importer;
+    let mut aabb = AABB::default();
+    if !world.bbox(&mut aabb) {
+        return Err(GltfError::Unsupported("scene has no triangle meshes".into()));
+    }
+    let cam = cam.unwrap_or_else(|| {
+        let center = aabb.centroid();
+        let radius = (aabb.max - aabb.min).length() * 0.5;
+        Camera::new(center + vec3a(0., 0., radius * 2.5), center, Vec3A::Y, 40., aspect_ratio)
+    });
+
+    let bvh: Arc<dyn Hitable> = Arc::new(FlatBvh::new(world));
+    Ok((vec![bvh], cam))
+}
@@ -1,39 +1,74 @@
 use std::f32::consts::PI;
 use std::sync::Arc;
+use std::time::Instant;
 
 use glam::*;
 use image::flat::NormalForm;
 use rand::Rng;
 
+use crate::bvh::*;
 use crate::math::*;
 use crate::material::{Material, Isotropic};
+use crate::phase::{Anisotropic, PhaseFunction};
 use crate::lib::RNG;
 use crate::texture::Texture;
 
 #[derive(Default, Clone)]
 pub struct HitRecord {
     pub p: Vec3A,
+    /// Shading normal, on the side of the incoming ray like `geom_norm`.
     pub norm: Vec3A,
     pub tang: Vec3A,
+    /// Normal of the actual surface, which `norm` can be tilted away from by mesh normals or normal maps.
+    pub geom_norm: Vec3A,
     pub t: f32,
     pub front_face: bool,
     pub mat: Option<Arc<dyn Material>>,
     pub uv: Vec2,
+    /// Change of `p` per unit of `uv`, zero where a shape has none. Unlike `tang` and `norm.cross(tang)`
+    /// they keep their length and don't have to be orthogonal or right-handed with the normal.
+    pub dpdu: Vec3A,
+    pub dpdv: Vec3A,
+    /// Placement of the object that was hit, made up of the `Transform`s above it, for materials
+    /// that intersect that object again in its own space, like `Subsurface`.
+    pub obj_to_world: Affine3A,
 }
 
 impl HitRecord {
-    fn set_face_normal(&mut self, r: &Ray, outward_normal: Vec3A) {
+    pub fn set_face_normal(&mut self, r: &Ray, outward_normal: Vec3A) {
         self.front_face = r.d.dot(outward_normal) < 0.0;
         self.norm = if self.front_face {
             outward_normal
         } else {
             -outward_normal
         };
+        self.geom_norm = self.norm;
     }
     pub fn world_to_local(&self, v: Vec3A) -> Vec3A {
         let bitang = self.norm.cross(self.tang);
         vec3a(v.dot(self.tang), v.dot(bitang), v.dot(self.norm))
     }
+    pub fn local_to_world(&self, v: Vec3A) -> Vec3A {
+        let bitang = self.norm.cross(self.tang);
+        v.x * self.tang + v.y * bitang + v.z * self.norm
+    }
+    /// Moves the frame of the hit out of the space of an object placed by `xform`, and `normal_mat`
+    /// the inverse transpose of its linear part. Leaves `p` to the caller, which often knows it better.
+    pub fn transform_frame(&mut self, xform: &Affine3A, normal_mat: &Mat3A) {
+        // dot(d, n) is invariant under the transform, so `front_face` stays valid
+        self.norm = (*normal_mat * self.norm).normalize();
+        self.geom_norm = (*normal_mat * self.geom_norm).normalize();
+        let tang = xform.transform_vector3a(self.tang);
+        let tang = tang - self.norm * self.norm.dot(tang);
+        self.tang = if tang.length_squared() > 1e-12 {
+            tang.normalize()
+        } else {
+            coordinate_system(self.norm)
+        };
+        self.dpdu = xform.transform_vector3a(self.dpdu);
+        self.dpdv = xform.transform_vector3a(self.dpdv);
+        self.obj_to_world = *xform * self.obj_to_world;
+    }
     pub fn world_to_local_with_rot(&self, v: Vec3A, rot: f32) -> Vec3A {
         let tang = rot.cos() * self.tang - rot.sin() * self.norm.cross(self.tang);
         let bitang = self.norm.cross(tang);
@@ -51,6 +86,76 @@ pub trait Hitable: Send + Sync {
     fn hit(&self, r: &Ray, t_min: f32, t_max: f32, rec: &mut HitRecord) -> bool;
     fn bbox(&self, aabb: &mut AABB) -> bool;
     fn memo(&self) -> String;
+
+    /// Bounds of the object moved by `xform`.
+    /// Shapes override this when they can do better than transforming `bbox` at a constant cost.
+    /// Meshes and BVHs don't, so placing a mesh many times doesn't go over its vertices each time.
+    fn transformed_bbox(&self, xform: &Affine3A, aabb: &mut AABB) -> bool {
+        let mut local = AABB::default();
+        if !self.bbox(&mut local) {
+            return false;
+        }
+        *aabb = local.transform(xform);
+        true
+    }
+
+    /// Emissive shapes that implement `sample` and `pdf` can be used for light sampling.
+    fn is_light(&self) -> bool {
+        false
+    }
+
+    /// Lights inside aggregates and wrappers, placed where they are in the space of `self`.
+    fn lights(&self) -> HitableList {
+        Vec::new()
+    }
+
+    /// Vector from `o` to a random point of the shape, its length is the distance to that point.
+    /// `None` for shapes that can't be sampled.
+    fn sample(&self, _o: Vec3A) -> Option<Vec3A> {
+        None
+    }
+
+    /// Solid angle density of `sample` returning the normalized direction `d` from `o`.
+    fn pdf(&self, _o: Vec3A, _d: Vec3A) -> f32 {
+        0.
+    }
+}
+
+/// Solid angle density of a point sampled uniformly over the area of `shape` as seen along `d`,
+/// summed over every point of the shape along `d` since any of them might have been sampled.
+pub(crate) fn area_pdf(shape: &dyn Hitable, area: f32, o: Vec3A, d: Vec3A) -> f32 {
+    let r = Ray { o, d, s: Vec2::ZERO };
+    let mut rec = HitRecord::default();
+    let mut t_min = 1e-3;
+    let mut pdf = 0.;
+    while shape.hit(&r, t_min, f32::MAX, &mut rec) {
+        let cos = d.dot(rec.geom_norm).abs();
+        if cos > 0. {
+            pdf += rec.t * rec.t / (cos * area);
+        }
+        t_min = rec.t * (1. + 1e-5) + 1e-5;
+    }
+    pdf
+}
+
+/// The objects of `objects` that are lights and the lights inside the others, see `Hitable::lights`.
+pub fn collect_lights(objects: &[Arc<dyn Hitable>]) -> HitableList {
+    let mut lights = Vec::new();
+    for o in objects {
+        if o.is_light() {
+            lights.push(o.clone());
+        } else {
+            lights.extend(o.lights());
+        }
+    }
+    lights
+}
+
+pub(crate) fn random_pair() -> (f32, f32) {
+    RNG.with(|rng| {
+        let mut rng = rng.borrow_mut();
+        (rng.gen::<f32>(), rng.gen::<f32>())
+    })
 }
 
 #[derive(Clone)]
@@ -69,6 +174,18 @@ impl Sphere {
         let v = theta / PI;
         vec2(u, v)
     }
+
+    /// dp/du and dp/dv of `get_uv` at the outward normal `n`, dp/dv vanishes at the poles.
+    fn uv_derivatives(&self, n: Vec3A) -> (Vec3A, Vec3A) {
+        let dpdu = 2. * PI * self.r * vec3a(n.z, 0., -n.x);
+        let sin_theta = (n.x * n.x + n.z * n.z).sqrt();
+        let dpdv = if sin_theta > 1e-6 {
+            PI * self.r * vec3a(-n.x * n.y, sin_theta * sin_theta, -n.z * n.y) / sin_theta
+        } else {
+            Vec3A::ZERO
+        };
+        (dpdu, dpdv)
+    }
 }
 
 impl Hitable for Sphere {
@@ -82,23 +199,30 @@ impl Hitable for Sphere {
             return false;
         }
         let sqrtd = discriminant.sqrt();
-        let mut root = (-half_b - sqrtd) / a;
-        if root < t_min || t_max < root {
-            root = (-half_b + sqrtd) / a;
+        // the far side is still visible through a cut out near one
+        for root in [(-half_b - sqrtd) / a, (-half_b + sqrtd) / a] {
             if root < t_min || t_max < root {
-                return false;
+                continue;
+            }
+            let p = r.at(root);
+            let outward_normal = (p - self.c) / self.r;
+            let uv = Sphere::get_uv(outward_normal);
+            if self.mat.is_cut_out(r, uv, p) {
+                continue;
             }
-        }
-
-        rec.t = root;
-        rec.p = r.at(rec.t);
-        let outward_normal = (rec.p - self.c) / self.r;
-        rec.tang = Vec3A::Y.cross(outward_normal).normalize();
-        rec.set_face_normal(r, outward_normal);
-        rec.uv = Sphere::get_uv(outward_normal);
-        rec.mat = Some(self.mat.clone());
 
-        true
+            rec.t = root;
+            rec.p = p;
+            let tang = Vec3A::Y.cross(outward_normal);
+            // the tangent follows the parallels and is undefined at the poles
+            rec.tang = if tang.length_squared() > 1e-12 { tang.normalize() } else { coordinate_system(outward_normal) };
+            rec.set_face_normal(r, outward_normal);
+            rec.uv = uv;
+            (rec.dpdu, rec.dpdv) = self.uv_derivatives(outward_normal);
+            rec.mat = Some(self.mat.clone());
+            return true;
+        }
+        false
     }
 
     fn bbox(&self, aabb: &mut AABB) -> bool {
@@ -109,6 +233,53 @@ impl Hitable for Sphere {
     fn memo(&self) -> String {
         self.name.clone()
     }
+    fn transformed_bbox(&self, xform: &Affine3A, aabb: &mut AABB) -> bool {
+        // the sphere becomes an ellipsoid, its extent along each axis is r times the row length
+        let m = xform.matrix3;
+        let c = xform.transform_point3a(self.c);
+        let half = vec3a(m.row(0).length(), m.row(1).length(), m.row(2).length()) * self.r;
+        aabb.min = c - half;
+        aabb.max = c + half;
+        true
+    }
+    fn is_light(&self) -> bool {
+        self.mat.is_emissive()
+    }
+    fn sample(&self, o: Vec3A) -> Option<Vec3A> {
+        let oc = self.c - o;
+        let dist2 = oc.length_squared();
+        let r2 = self.r * self.r;
+        if dist2 <= r2 {
+            // from the inside every point is visible
+            return Some(self.c + self.r * random_on_unit_sphere() - o);
+        }
+        // uniform direction in the cone subtended by the sphere
+        let cos_max = (1. - r2 / dist2).sqrt();
+        let (u1, u2) = random_pair();
+        let z = 1. + u2 * (cos_max - 1.);
+        let sin = (1. - z * z).max(0.).sqrt();
+        let (sin_phi, cos_phi) = (2. * PI * u1).sin_cos();
+        let w = oc / dist2.sqrt();
+        let t = coordinate_system(w);
+        let b = w.cross(t);
+        let d = t * (cos_phi * sin) + b * (sin_phi * sin) + w * z;
+        let proj = d.dot(oc);
+        let dist = proj - (r2 - (dist2 - proj * proj)).max(0.).sqrt();
+        Some(d * dist)
+    }
+    fn pdf(&self, o: Vec3A, d: Vec3A) -> f32 {
+        let dist2 = (self.c - o).length_squared();
+        let r2 = self.r * self.r;
+        if dist2 <= r2 {
+            return area_pdf(self, 4. * PI * r2, o, d);
+        }
+        let mut rec = HitRecord::default();
+        if !self.hit(&Ray { o, d, s: Vec2::ZERO }, 1e-3, f32::MAX, &mut rec) {
+            return 0.;
+        }
+        let cos_max = (1. - r2 / dist2).sqrt();
+        1. / (2. * PI * (1. - cos_max))
+    }
 }
 
 pub type HitableList = Vec<Arc<dyn Hitable>>;
@@ -153,6 +324,24 @@ impl Hitable for HitableList {
     fn memo(&self) -> String {
         "HitableList".to_string()
     }
+    fn lights(&self) -> HitableList {
+        collect_lights(self)
+    }
+    fn transformed_bbox(&self, xform: &Affine3A, aabb: &mut AABB) -> bool {
+        if self.is_empty() {
+            return false;
+        }
+        let mut acc = AABB::empty();
+        let mut bbox = AABB::default();
+        for o in self {
+            if !o.transformed_bbox(xform, &mut bbox) {
+                return false;
+            }
+            acc = acc.surround(bbox);
+        }
+        *aabb = acc;
+        true
+    }
 }
 
 pub struct BvhNode {
@@ -161,63 +350,82 @@ pub struct BvhNode {
     right: Arc<dyn Hitable>,
 }
 
-fn box_compare(a: &Arc<dyn Hitable>, b: &Arc<dyn Hitable>, axis: usize) -> std::cmp::Ordering {
-    let mut box_a = AABB::default();
-    let mut box_b = AABB::default();
-
-    let a_result = a.bbox(&mut box_a);
-    let b_result = b.bbox(&mut box_b);
-    if a_result == false && b_result == false {
-        eprintln!("No bounding box in bvh_node constructor.");
-    }
-    box_a.min[axis].total_cmp(&box_b.min[axis])
-}
-
 impl BvhNode {
     pub fn new(
         objects: &mut HitableList,
         start: usize,
         end: usize,
     ) -> Self {
-        let axis: usize = RNG.with(|rng| {
-            rng.borrow_mut().gen_range(0..3)
-        });
-        let object_span = end - start;
-        let (left, right) = match object_span {
-            0 => unimplemented!(),
-            1 => (objects[start].clone(), objects[start].clone()),
-            v => {
-                objects[start..end].sort_by(|a, b| box_compare(a, b, axis));
-                if v == 2 {
-                    (objects[start].clone(), objects[start+1].clone())
-                } else {
-                    let mid = start + v / 2;
-                    let left: Arc<dyn Hitable> = Arc::new(BvhNode::new(
-                        objects,
-                        start,
-                        mid,
-                    ));
-                    let right: Arc<dyn Hitable> = Arc::new(BvhNode::new(
-                        objects,
-                        mid,
-                        end,
-                    ));
-                    (left, right)
-                }
+        Self::with_options(objects, start, end, BvhOptions::default()).0
+    }
+
+    pub fn with_options(
+        objects: &mut HitableList,
+        start: usize,
+        end: usize,
+        options: BvhOptions,
+    ) -> (Self, BvhStats) {
+        let timer = Instant::now();
+        let mut prims: Vec<BuildPrim> = objects[start..end].iter().enumerate().map(|(i, o)| {
+            let mut aabb = AABB::default();
+            if !o.bbox(&mut aabb) {
+                eprintln!("No bounding box in bvh_node constructor.");
+            }
+            BuildPrim::new(start + i, aabb)
+        }).collect();
+        let aabb = prims_bounds(&prims);
+
+        let mut stats = BvhStats::default();
+        let root = match split_prims(&mut prims, &aabb, options) {
+            Some((mid, _)) => Self::node(objects, &mut prims, mid, aabb, options, 0, &mut stats),
+            None => {
+                // everything fits into one leaf
+                stats.add_node(&aabb, 0);
+                let left = Self::leaf(objects, &prims, 1, &mut stats);
+                Self { aabb, left, right: Arc::new(HitableList::new()) }
             },
         };
+        stats.finish(&aabb, timer.elapsed().as_secs_f32());
+        (root, stats)
+    }
+
+    fn node(
+        objects: &HitableList,
+        prims: &mut [BuildPrim],
+        mid: usize,
+        aabb: AABB,
+        options: BvhOptions,
+        depth: usize,
+        stats: &mut BvhStats,
+    ) -> Self {
+        stats.add_node(&aabb, depth);
+        let (l, r) = prims.split_at_mut(mid);
+        let left = Self::subtree(objects, l, options, depth + 1, stats);
+        let right = Self::subtree(objects, r, options, depth + 1, stats);
+        Self { aabb, left, right }
+    }
 
-        let mut box_a = AABB::default();
-        let mut box_b = AABB::default();
-
-        let a_result = left.bbox(&mut box_a);
-        let b_result = right.bbox(&mut box_b);
-        if a_result == false && b_result == false {
-            eprintln!("No bounding box in bvh_node constructor.");
+    fn subtree(
+        objects: &HitableList,
+        prims: &mut [BuildPrim],
+        options: BvhOptions,
+        depth: usize,
+        stats: &mut BvhStats,
+    ) -> Arc<dyn Hitable> {
+        let aabb = prims_bounds(prims);
+        match split_prims(prims, &aabb, options) {
+            Some((mid, _)) => Arc::new(Self::node(objects, prims, mid, aabb, options, depth, stats)),
+            None => Self::leaf(objects, prims, depth, stats),
         }
-        let aabb = box_a.surround(box_b);
+    }
 
-        Self { aabb, left, right }
+    fn leaf(objects: &HitableList, prims: &[BuildPrim], depth: usize, stats: &mut BvhStats) -> Arc<dyn Hitable> {
+        stats.add_leaf(&prims_bounds(prims), prims.len(), depth);
+        if prims.len() == 1 {
+            objects[prims[0].index].clone()
+        } else {
+            Arc::new(prims.iter().map(|p| objects[p.index].clone()).collect::<HitableList>())
+        }
     }
 }
 
@@ -225,6 +433,9 @@ impl Hitable for BvhNode {
     fn memo(&self) -> String {
         "BvhNode".to_string()
     }
+    fn lights(&self) -> HitableList {
+        collect_lights(&[self.left.clone(), self.right.clone()])
+    }
     fn bbox(&self, aabb: &mut AABB) -> bool {
         *aabb = self.aabb;
         true
@@ -240,6 +451,60 @@ impl Hitable for BvhNode {
     }
 }
 
+/// BVH flattened into one node array, see `LinearBvh`.
+pub struct FlatBvh {
+    bvh: LinearBvh,
+    objects: HitableList,
+}
+
+impl FlatBvh {
+    pub fn new(objects: HitableList) -> Self {
+        Self::with_options(objects, BvhOptions::default()).0
+    }
+
+    pub fn with_options(objects: HitableList, options: BvhOptions) -> (Self, BvhStats) {
+        let prims = objects.iter().enumerate().map(|(i, o)| {
+            let mut aabb = AABB::default();
+            if !o.bbox(&mut aabb) {
+                eprintln!("No bounding box in bvh_node constructor.");
+            }
+            BuildPrim::new(i, aabb)
+        }).collect();
+        let (bvh, stats) = LinearBvh::new(prims, options);
+        (Self { bvh, objects }, stats)
+    }
+}
+
+impl Hitable for FlatBvh {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32, rec: &mut HitRecord) -> bool {
+        self.bvh.traverse(r, t_min, t_max, |i, t_max| {
+            if self.objects[i].hit(r, t_min, t_max, rec) {
+                Some(rec.t)
+            } else {
+                None
+            }
+        })
+    }
+
+    fn bbox(&self, aabb: &mut AABB) -> bool {
+        match self.bvh.bounds() {
+            Some(b) => {
+                *aabb = b;
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn memo(&self) -> String {
+        "FlatBvh".to_string()
+    }
+
+    fn lights(&self) -> HitableList {
+        collect_lights(&self.objects)
+    }
+}
+
 
 #[derive(Clone)]
 pub struct XYRect {
@@ -259,13 +524,19 @@ impl Hitable for XYRect {
             return false;
         }
 
-        let uv = (p - self.min) / (self.max - self.min);
-        rec.uv = uv.xy();
+        let uv = ((p - self.min) / (self.max - self.min)).xy();
+        if self.mat.is_cut_out(r, uv, p) {
+            return false;
+        }
+        rec.uv = uv;
         rec.p = p;
         rec.t = t;
 
         let outward_normal = Vec3A::Z;
         rec.set_face_normal(r, outward_normal);
+        rec.tang = Vec3A::X;
+        rec.dpdu = vec3a(self.max.x - self.min.x, 0., 0.);
+        rec.dpdv = vec3a(0., self.max.y - self.min.y, 0.);
         rec.mat = Some(self.mat.clone());
 
         true
@@ -279,6 +550,17 @@ impl Hitable for XYRect {
     fn memo(&self) -> String {
         "XYRect".into()
     }
+    fn is_light(&self) -> bool {
+        self.mat.is_emissive()
+    }
+    fn sample(&self, o: Vec3A) -> Option<Vec3A> {
+        let (u, v) = random_pair();
+        Some(vec3a(lerp(self.min.x, self.max.x, u), lerp(self.min.y, self.max.y, v), self.min.z) - o)
+    }
+    fn pdf(&self, o: Vec3A, d: Vec3A) -> f32 {
+        let area = (self.max.x - self.min.x) * (self.max.y - self.min.y);
+        area_pdf(self, area, o, d)
+    }
 }
 
 #[derive(Clone)]
@@ -299,13 +581,19 @@ impl Hitable for XZRect {
             return false;
         }
 
-        let uv = (p - self.min) / (self.max - self.min);
-        rec.uv = uv.xz();
+        let uv = ((p - self.min) / (self.max - self.min)).xz();
+        if self.mat.is_cut_out(r, uv, p) {
+            return false;
+        }
+        rec.uv = uv;
         rec.p = p;
         rec.t = t;
 
         let outward_normal = Vec3A::Y;
         rec.set_face_normal(r, outward_normal);
+        rec.tang = Vec3A::X;
+        rec.dpdu = vec3a(self.max.x - self.min.x, 0., 0.);
+        rec.dpdv = vec3a(0., 0., self.max.z - self.min.z);
         rec.mat = Some(self.mat.clone());
 
         true
@@ -319,6 +607,17 @@ impl Hitable for XZRect {
     fn memo(&self) -> String {
         "XZRect".into()
     }
+    fn is_light(&self) -> bool {
+        self.mat.is_emissive()
+    }
+    fn sample(&self, o: Vec3A) -> Option<Vec3A> {
+        let (u, v) = random_pair();
+        Some(vec3a(lerp(self.min.x, self.max.x, u), self.min.y, lerp(self.min.z, self.max.z, v)) - o)
+    }
+    fn pdf(&self, o: Vec3A, d: Vec3A) -> f32 {
+        let area = (self.max.x - self.min.x) * (self.max.z - self.min.z);
+        area_pdf(self, area, o, d)
+    }
 }
 
 #[derive(Clone)]
@@ -339,13 +638,19 @@ impl Hitable for YZRect {
             return false;
         }
 
-        let uv = (p - self.min) / (self.max - self.min);
-        rec.uv = uv.yz();
+        let uv = ((p - self.min) / (self.max - self.min)).yz();
+        if self.mat.is_cut_out(r, uv, p) {
+            return false;
+        }
+        rec.uv = uv;
         rec.p = p;
         rec.t = t;
 
         let outward_normal = Vec3A::X;
         rec.set_face_normal(r, outward_normal);
+        rec.tang = Vec3A::Y;
+        rec.dpdu = vec3a(0., self.max.y - self.min.y, 0.);
+        rec.dpdv = vec3a(0., 0., self.max.z - self.min.z);
         rec.mat = Some(self.mat.clone());
 
         true
@@ -359,6 +664,17 @@ impl Hitable for YZRect {
     fn memo(&self) -> String {
         "YZRect".into()
     }
+    fn is_light(&self) -> bool {
+        self.mat.is_emissive()
+    }
+    fn sample(&self, o: Vec3A) -> Option<Vec3A> {
+        let (u, v) = random_pair();
+        Some(vec3a(self.min.x, lerp(self.min.y, self.max.y, u), lerp(self.min.z, self.max.z, v)) - o)
+    }
+    fn pdf(&self, o: Vec3A, d: Vec3A) -> f32 {
+        let area = (self.max.y - self.min.y) * (self.max.z - self.min.z);
+        area_pdf(self, area, o, d)
+    }
 }
 
 pub struct GBox {
@@ -397,125 +713,88 @@ impl Hitable for GBox {
     }
 
     fn memo(&self) -> String {
-        todo!()
+        "GBox".into()
+    }
+
+    fn lights(&self) -> HitableList {
+        collect_lights(&self.sides)
     }
 }
 
-pub struct Translate {
-    pub offset: Vec3A,
-    pub ptr: Arc<dyn Hitable>,
+/// Places `object` with an arbitrary affine transform: rotation, non-uniform scale, shear, translation.
+/// The object is intersected in its own space, so it can be shared by many transforms.
+#[derive(Clone)]
+pub struct Transform {
+    object: Arc<dyn Hitable>,
+    obj_to_world: Affine3A,
+    world_to_obj: Affine3A,
+    // inverse transpose of the linear part, for normals
+    normal_mat: Mat3A,
+    aabb: AABB,
+    has_box: bool,
+}
+
+impl Transform {
+    pub fn new(object: Arc<dyn Hitable>, obj_to_world: Affine3A) -> Self {
+        let world_to_obj = obj_to_world.inverse();
+        let normal_mat = world_to_obj.matrix3.transpose();
+        let mut aabb = AABB::default();
+        let has_box = object.transformed_bbox(&obj_to_world, &mut aabb);
+        Self { object, obj_to_world, world_to_obj, normal_mat, aabb, has_box }
+    }
 }
 
-impl Hitable for Translate {
+impl Hitable for Transform {
     fn hit(&self, r: &Ray, t_min: f32, t_max: f32, rec: &mut HitRecord) -> bool {
-        let moved_r = Ray { o: r.o - self.offset, d: r.d, s: r.s};
-        if self.ptr.hit(&moved_r, t_min, t_max, rec) {
-            rec.p += self.offset;
-            true
-        } else {
-            false
+        // the direction is left unnormalized so `t` means the same in both spaces
+        let obj_r = Ray {
+            o: self.world_to_obj.transform_point3a(r.o),
+            d: self.world_to_obj.transform_vector3a(r.d),
+            s: r.s,
+        };
+        if !self.object.hit(&obj_r, t_min, t_max, rec) {
+            return false;
         }
+        rec.p = r.at(rec.t);
+        rec.transform_frame(&self.obj_to_world, &self.normal_mat);
+        true
     }
 
     fn bbox(&self, aabb: &mut AABB) -> bool {
-        let mut get_aabb = AABB::default();
-        if self.ptr.bbox(&mut get_aabb) {
-            *aabb = AABB {
-                min: get_aabb.min + self.offset,
-                max: get_aabb.max + self.offset,
-            };
-            true
-        } else {
-            false
-        }
+        *aabb = self.aabb;
+        self.has_box
     }
 
     fn memo(&self) -> String {
-        todo!()
+        format!("Transform of {}", self.object.memo())
     }
-}
-
-pub struct RotateY {
-    ptr: Arc<dyn Hitable>,
-    angle: f32,
-    sin_theta: f32,
-    cos_theta: f32,
-    has_box: bool,
-    aabb: AABB,
-}
-
-impl RotateY {
-    pub fn new(ptr: Arc<dyn Hitable>, angle: f32) -> Self {
-        let radians = angle.to_radians();
-        let (sin_theta, cos_theta) = radians.sin_cos();
-        let mut aabb_ = AABB::default();
-        let has_box = ptr.bbox(&mut aabb_);
-        let mut min = vec3a(f32::INFINITY, f32::INFINITY, f32::INFINITY);
-        let mut max = vec3a(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY);
-        for i in 0..2 {
-            for j in 0..2 {
-                for k in 0..2 {
-                    let x = if i == 0 {aabb_.min.x} else {aabb_.max.x};
-                    let y = if j == 0 {aabb_.min.y} else {aabb_.max.y};
-                    let z = if k == 0 {aabb_.min.z} else {aabb_.max.z};
-
-                    let new_x = cos_theta * x + sin_theta * z;
-                    let new_z = -sin_theta * x + cos_theta * z;
-
-                    let tester = vec3a(new_x, y, new_z);
-
-                    for c in 0..3 {
-                        min[c] = min[c].min(tester[c]);
-                        max[c] = max[c].max(tester[c]);
-                    }
-                }
-            }
-        }
-        let aabb = AABB { min, max };
 
-        Self { ptr, angle, sin_theta, cos_theta, has_box, aabb }
+    fn is_light(&self) -> bool {
+        self.object.is_light()
     }
-}
-
-
-impl Hitable for RotateY {
-    fn hit(&self, r: &Ray, t_min: f32, t_max: f32, rec: &mut HitRecord) -> bool {
-        let mut o = r.o;
-        let mut d = r.d;
-
-        o.x = self.cos_theta * r.o.x - self.sin_theta * r.o.z;
-        o.z = self.sin_theta * r.o.x + self.cos_theta * r.o.z;
-
-        d.x = self.cos_theta * r.d.x - self.sin_theta * r.d.z;
-        d.z = self.sin_theta * r.d.x + self.cos_theta * r.d.z;
 
-        let rot_r = Ray {o, d, s: r.s};
-
-        if self.ptr.hit(&rot_r, t_min, t_max, rec) {
-            let mut p = rec.p;
-            let mut n = rec.norm;
-
-            p.x =  self.cos_theta * rec.p.x + self.sin_theta * rec.p.z;
-            p.z = -self.sin_theta * rec.p.x + self.cos_theta * rec.p.z;
-
-            n.x =  self.cos_theta * rec.norm.x + self.sin_theta * rec.norm.z;
-            n.z = -self.sin_theta * rec.norm.x + self.cos_theta * rec.norm.z;
+    fn lights(&self) -> HitableList {
+        self.object.lights().into_iter()
+            .map(|l| Arc::new(Transform::new(l, self.obj_to_world)) as Arc<dyn Hitable>)
+            .collect()
+    }
 
-            rec.p = p;
-            rec.set_face_normal(&rot_r, n);
-            true
-        } else {
-            false
-        }
+    fn sample(&self, o: Vec3A) -> Option<Vec3A> {
+        let v = self.object.sample(self.world_to_obj.transform_point3a(o))?;
+        Some(self.obj_to_world.transform_vector3a(v))
     }
 
-    fn bbox(&self, aabb: &mut AABB) -> bool {
-        *aabb = self.aabb;
-        self.has_box
+    /// Directions change their density by the Jacobian `|det A| / |A u|^3` of `u -> A u / |A u|`,
+    /// with `A u` along `d` for the unit `u` in object space.
+    fn pdf(&self, o: Vec3A, d: Vec3A) -> f32 {
+        let d_obj = self.world_to_obj.transform_vector3a(d);
+        let len = d_obj.length();
+        let pdf = self.object.pdf(self.world_to_obj.transform_point3a(o), d_obj / len);
+        pdf / (len * len * len * self.obj_to_world.matrix3.determinant().abs())
     }
 
-    fn memo(&self) -> String {
-        todo!()
+    fn transformed_bbox(&self, xform: &Affine3A, aabb: &mut AABB) -> bool {
+        self.object.transformed_bbox(&(*xform * self.obj_to_world), aabb)
     }
 }
 
@@ -530,6 +809,11 @@ impl ConstantMedium {
     pub fn new(boundary: Arc<dyn Hitable>, density: f32, phase_fn: Arc<dyn Texture>) -> Self {
         Self { boundary, phase_fn: Arc::new(Isotropic{albedo: phase_fn}), neg_inv_density: -1./density }
     }
+
+    /// Scattering by `phase` instead of uniformly, e.g. forward into a glow around lights.
+    pub fn with_phase(boundary: Arc<dyn Hitable>, density: f32, albedo: Arc<dyn Texture>, phase: Arc<dyn PhaseFunction>) -> Self {
+        Self { boundary, phase_fn: Arc::new(Anisotropic { albedo, phase }), neg_inv_density: -1./density }
+    }
 }
 
 impl Hitable for ConstantMedium {
@@ -571,7 +855,10 @@ impl Hitable for ConstantMedium {
         if debugging {
             eprintln!("hit_dist: {}, rec.t: {}, rec.t: {}", hit_dist, rec.t, rec.p);
         }
+        // any frame works, the phase functions only depend on the angle between `wi` and `wo`
         rec.norm = Vec3A::X;
+        rec.tang = Vec3A::Y;
+        rec.geom_norm = Vec3A::X;
         rec.front_face = true;
         rec.mat = Some(self.phase_fn.clone());
 
@@ -583,6 +870,10 @@ impl Hitable for ConstantMedium {
     }
 
     fn memo(&self) -> String {
-        todo!()
+        format!("ConstantMedium in {}", self.boundary.memo())
+    }
+
+    fn transformed_bbox(&self, xform: &Affine3A, aabb: &mut AABB) -> bool {
+        self.boundary.transformed_bbox(xform, aabb)
     }
 }
@@ -0,0 +1,78 @@
+use std::sync::Arc;
+
+use glam::*;
+
+use crate::bvh::*;
+use crate::hitable::{collect_lights, HitRecord, Hitable, HitableList, Transform};
+use crate::math::*;
+
+/// One placement of a shared bottom level `Hitable`.
+/// The object is intersected in its own space, so any number of instances share its geometry.
+pub type Instance = Transform;
+
+/// Top level BVH over instances, built over their world space bounds.
+pub struct InstanceBvh {
+    bvh: LinearBvh,
+    instances: Vec<Instance>,
+}
+
+impl InstanceBvh {
+    pub fn new(instances: Vec<Instance>) -> Self {
+        Self::with_options(instances, BvhOptions::default()).0
+    }
+
+    pub fn with_options(instances: Vec<Instance>, options: BvhOptions) -> (Self, BvhStats) {
+        let prims = instances.iter().enumerate()
+            .map(|(i, inst)| {
+                let mut aabb = AABB::default();
+                inst.bbox(&mut aabb);
+                BuildPrim::new(i, aabb)
+            })
+            .collect();
+        let (bvh, stats) = LinearBvh::new(prims, options);
+        (Self { bvh, instances }, stats)
+    }
+}
+
+impl Hitable for InstanceBvh {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32, rec: &mut HitRecord) -> bool {
+        self.bvh.traverse(r, t_min, t_max, |i, t_max| {
+            if self.instances[i].hit(r, t_min, t_max, rec) {
+                Some(rec.t)
+            } else {
+                None
+            }
+        })
+    }
+
+    fn bbox(&self, aabb: &mut AABB) -> bool {
+        match self.bvh.bounds() {
+            Some(b) => {
+                *aabb = b;
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn memo(&self) -> String {
+        "InstanceBvh".to_string()
+    }
+
+    fn lights(&self) -> HitableList {
+        let instances: HitableList = self.instances.iter().map(|inst| Arc::new(inst.clone()) as Arc<dyn Hitable>).collect();
+        collect_lights(&instances)
+    }
+
+    fn transformed_bbox(&self, xform: &Affine3A, aabb: &mut AABB) -> bool {
+        let mut acc = AABB::empty();
+        let mut bbox = AABB::default();
+        for inst in &self.instances {
+            if inst.transformed_bbox(xform, &mut bbox) {
+                acc = acc.surround(bbox);
+            }
+        }
+        *aabb = acc;
+        !self.instances.is_empty()
+    }
+}
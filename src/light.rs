@@ -0,0 +1,47 @@
+use glam::*;
+use rand::Rng;
+
+use crate::hitable::*;
+use crate::lib::RNG;
+use crate::math::Ray;
+
+/// The emissive hitables of a scene that can be sampled directly, see `Hitable::is_light`.
+#[derive(Clone)]
+pub struct LightList {
+    pub lights: HitableList,
+}
+
+impl LightList {
+    /// Collects the lights of `world`, including those inside transforms, BVHs and instances.
+    pub fn new(world: &HitableList) -> Self {
+        Self { lights: collect_lights(world) }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lights.is_empty()
+    }
+
+    /// Picks one light uniformly and returns the vector from `o` to a point on it.
+    pub fn sample(&self, o: Vec3A) -> Option<Vec3A> {
+        if self.lights.is_empty() {
+            return None;
+        }
+        let i = RNG.with(|rng| rng.borrow_mut().gen_range(0..self.lights.len()));
+        self.lights[i].sample(o)
+    }
+
+    /// Density of `sample` returning the normalized direction `d` from `o`,
+    /// summed over all lights since any of them might have produced it.
+    pub fn pdf(&self, o: Vec3A, d: Vec3A) -> f32 {
+        let sum: f32 = self.lights.iter().map(|l| l.pdf(o, d)).sum();
+        sum / self.lights.len() as f32
+    }
+
+    /// Whether the closest hit of `r`, at `t`, lies on one of the lights.
+    pub fn is_hit_at(&self, r: &Ray, t: f32) -> bool {
+        let mut rec = HitRecord::default();
+        self.lights.iter().any(|l| {
+            l.hit(r, 1e-3, f32::MAX, &mut rec) && (rec.t - t).abs() <= 1e-4 * t
+        })
+    }
+}
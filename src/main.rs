@@ -1,9 +1,10 @@
 #![cfg_attr(debug_assertions, allow(dead_code, unused_imports, unused_variables, unused_mut))]
 
+use std::sync::Arc;
 use std::sync::mpsc::channel;
 
 use image::{ImageBuffer, RgbImage, Rgb};
-use glam::Vec3A;
+use glam::{vec2, Vec3A};
 
 mod math;
 use math::*;
@@ -13,8 +14,27 @@ mod camera;
 mod hitable;
 use hitable::*;
 
+mod bvh;
+mod instance;
+
+mod mesh;
+mod obj;
+mod gltf_import;
+
+mod light;
+use light::*;
+
 mod material;
+use material::LobeFlags;
 mod pbr;
+mod bump;
+mod subsurface;
+mod phase;
+mod volume;
+use volume::{Medium, SCENE_MEDIUM, CAMERA_MEDIUM};
+mod sparse_grid;
+mod sky;
+use sky::{SunSky, SUN_SKY};
 
 mod utils;
 use utils::*;
@@ -35,28 +55,133 @@ use chrono::prelude::*;
 
 const MAX_DEPTH: i32 = 50;
 
-fn ray_color(r: Ray, world: &HitableList, depth: i32) -> Vec3A {
+/// Next event estimation: one shadow ray towards a point sampled on the lights,
+/// weighted against the material sampling the same direction. The shadow ray goes through `medium`.
+fn sample_lights(r: &Ray, rec: &HitRecord, wi: Vec3A, world: &HitableList, lights: &LightList, medium: Option<&Medium>) -> Vec3A {
+    let mat = rec.mat.as_ref().unwrap();
+    let d = match lights.sample(rec.p).and_then(|v| v.try_normalize()) {
+        Some(d) => d,
+        None => return Vec3A::ZERO,
+    };
+    let wo = rec.world_to_local(d);
+    let f = mat.eval(rec, wi, wo);
+    let pdf = lights.pdf(rec.p, d);
+    if f == Vec3A::ZERO || pdf <= 0. {
+        return Vec3A::ZERO;
+    }
+    let shadow = Ray {o: rec.p, d, s: r.s};
+    let mut light_rec = HitRecord::default();
+    if !world.hit(&shadow, 1e-3, f32::MAX, &mut light_rec) || !lights.is_hit_at(&shadow, light_rec.t) {
+        return Vec3A::ZERO;
+    }
+    let tr = medium.map_or(Vec3A::ONE, |m| m.transmittance(light_rec.t));
+    let weight = power_heuristic(pdf, mat.pdf(rec, wi, wo));
+    f * tr * light_rec.mat.as_ref().unwrap().emitted(light_rec.uv, light_rec.p) * weight / pdf
+}
+
+/// Like `sample_lights` but towards the sun and sky, which only shine on a point nothing hides them from.
+fn sample_sky(r: &Ray, rec: &HitRecord, wi: Vec3A, world: &HitableList, sky: &SunSky, medium: Option<&Medium>) -> Vec3A {
+    let mat = rec.mat.as_ref().unwrap();
+    let d = sky.sample();
+    let wo = rec.world_to_local(d);
+    let f = mat.eval(rec, wi, wo);
+    let pdf = sky.pdf(d);
+    if f == Vec3A::ZERO || pdf <= 0. {
+        return Vec3A::ZERO;
+    }
+    let shadow = Ray {o: rec.p, d, s: r.s};
+    if world.hit(&shadow, 1e-3, f32::MAX, &mut HitRecord::default()) {
+        return Vec3A::ZERO;
+    }
+    let tr = medium.map_or(Vec3A::ONE, |m| m.transmittance(f32::INFINITY));
+    let weight = power_heuristic(pdf, mat.pdf(rec, wi, wo));
+    f * tr * sky.radiance(d) * weight / pdf
+}
+
+/// `bsdf_pdf` is the density the previous vertex sampled `r` with, if it also sampled the lights or the sky.
+/// Emission of a light or the sky found that way gets its share of the multiple importance sampling.
+/// `medium` is the one `r` travels through, which may scatter it before it reaches anything.
+fn ray_color(r: Ray, world: &HitableList, lights: &LightList, depth: i32, bsdf_pdf: Option<f32>, medium: Option<Arc<Medium>>) -> Vec3A {
     assert!(vec3a_near_one(r.d));
     if depth > MAX_DEPTH {
         return Vec3A::ZERO;
     }
     let mut rec = HitRecord::default();
-    if world.hit(&r, 1e-3, f32::MAX, &mut rec) {
-        let mut scattered = Ray {o: Vec3A::ZERO, d: Vec3A::ZERO, s: r.s};
-        let mut attenuation = Vec3A::ONE;
-        let mut ret = rec.mat.as_ref().unwrap().emitted(rec.uv, rec.p);
-        if rec.mat.as_ref().unwrap().scatter(&r, &rec, &mut attenuation, &mut scattered) {
-            // let russian_roulette = RNG.with(|rng| rng.borrow_mut().gen::<f32>());
-            // let threshold = attenuation.max_element();
-            // if russian_roulette < threshold {
-            //     ret += attenuation * ray_color(scattered, &world, depth+1) / threshold;
-            // }
-            ret += attenuation * ray_color(scattered, &world, depth+1);
+    let hit = world.hit(&r, 1e-3, f32::MAX, &mut rec);
+    let mut beta = Vec3A::ONE;
+    if let Some(m) = &medium {
+        let u = vec3a_random();
+        let (t, weight) = m.sample_distance(if hit { rec.t } else { f32::INFINITY }, vec2(u.x, u.y));
+        if weight == Vec3A::ZERO {
+            return Vec3A::ZERO;
         }
-        ret
+        beta = weight;
+        if let Some(t) = t {
+            let rec = m.scatter_record(&r, t);
+            return beta * scatter(&r, &rec, world, lights, depth, bsdf_pdf, medium);
+        }
+    }
+    if hit {
+        beta * scatter(&r, &rec, world, lights, depth, bsdf_pdf, medium)
     } else {
-        SKY_COLOR.get().unwrap()(r.d)
+        let weight = match (bsdf_pdf, SUN_SKY.get()) {
+            (Some(bsdf_pdf), Some(sky)) => power_heuristic(bsdf_pdf, sky.pdf(r.d)),
+            _ => 1.,
+        };
+        beta * weight * SKY_COLOR.get().unwrap()(r.d)
+    }
+}
+
+/// Emission at `rec` plus the light scattered there, from a surface or inside `medium`.
+fn scatter(r: &Ray, rec: &HitRecord, world: &HitableList, lights: &LightList, depth: i32, bsdf_pdf: Option<f32>, medium: Option<Arc<Medium>>) -> Vec3A {
+    let mat = rec.mat.clone().unwrap();
+    let mut ret = mat.emitted(rec.uv, rec.p);
+    if let Some(bsdf_pdf) = bsdf_pdf {
+        if !lights.is_empty() && lights.is_hit_at(r, rec.t) {
+            ret *= power_heuristic(bsdf_pdf, lights.pdf(r.o, r.d));
+        }
+    }
+    let wi = rec.world_to_local(-r.d);
+    // delta lobes have nothing to weigh a light sample with
+    let nee_lights = Some(lights).filter(|l| !l.is_empty() && mat.flags().is_non_specular());
+    if let Some(nee_lights) = nee_lights {
+        ret += sample_lights(r, rec, wi, world, nee_lights, medium.as_deref());
+    }
+    let sky = SUN_SKY.get().filter(|_| mat.flags().is_non_specular());
+    if let Some(sky) = sky {
+        ret += sample_sky(r, rec, wi, world, sky, medium.as_deref());
+    }
+    let u = vec3a_random();
+    if let Some(bs) = mat.sample(rec, wi, u.x, vec2(u.y, u.z)) {
+        // let russian_roulette = RNG.with(|rng| rng.borrow_mut().gen::<f32>());
+        // let threshold = bs.weight.max_element();
+        // if russian_roulette < threshold {
+        //     ret += bs.weight * ray_color(scattered, &world, depth+1) / threshold;
+        // }
+        let d = rec.local_to_world(bs.wo).normalize();
+        if let Some(exit) = &bs.exit {
+            // the path resurfaces elsewhere, a vertex of its own which the sample got no further than
+            let r = Ray {o: rec.p, d, s: r.s};
+            return ret + bs.weight * scatter(&r, exit, world, lights, depth+1, None, medium);
+        }
+        // surface bounces leave from the side they point to, specular ones and media keep the exact point
+        let o = if bs.flags.is_specular() || bs.flags.contains(LobeFlags::TRANSMISSION) {
+            rec.p
+        } else {
+            offset_hit_point(rec.p, rec.geom_norm * d.dot(rec.geom_norm).signum())
+        };
+        // refracting into an object with a medium enters it, refracting out goes back to the scene's
+        let medium = match mat.interior() {
+            Some(interior) if d.dot(rec.geom_norm) < 0. => {
+                if rec.front_face { Some(interior) } else { SCENE_MEDIUM.get().cloned() }
+            }
+            _ => medium,
+        };
+        let scattered = Ray {o, d, s: r.s};
+        let bsdf_pdf = (nee_lights.is_some() || sky.is_some()).then_some(bs.pdf).filter(|_| !bs.flags.is_specular());
+        ret += bs.weight * ray_color(scattered, world, lights, depth+1, bsdf_pdf, medium);
     }
+    ret
 }
 
 fn main() {
@@ -71,12 +196,13 @@ fn main() {
 
     let (tx, rx) = channel();
     let pool = threadpool::Builder::new().build();
-    let (world, cam) = test_sphere(aspect_ratio);
+    let (world, lights, cam) = test_sphere(aspect_ratio);
 
     let mut img: RgbImage = ImageBuffer::new(nx, ny);
     for i in 0..nx {
         let tx = tx.clone();
         let world = world.clone();
+        let lights = lights.clone();
         pool.execute(move || {
             RNG.with(|rng| {
                 *rng.borrow_mut() = SmallRng::seed_from_u64(95 + i as u64);
@@ -93,7 +219,7 @@ fn main() {
                     }
                 });
                 for r in rays {
-                    c += ray_color(r, &world, 0);
+                    c += ray_color(r, &world, &lights, 0, None, CAMERA_MEDIUM.get().or(SCENE_MEDIUM.get()).cloned());
                 }
                 c /= samples_per_pixel as f32;
                 c = c.powf(1.0 / 2.0);
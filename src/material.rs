@@ -1,17 +1,94 @@
+use std::f32::consts::*;
+use std::ops::BitOr;
 use std::sync::Arc;
 
 use glam::*;
-use rand::Rng;
 use crate::math::*;
 use crate::hitable::HitRecord;
 use crate::texture::Texture;
-use crate::lib::RNG;
+use crate::pbr::ThinFilm;
+use crate::volume::Medium;
 
+/// Kinds of scattering a BSDF sample or a whole material can produce.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LobeFlags(u8);
+
+impl LobeFlags {
+    pub const NONE: Self = Self(0);
+    pub const REFLECTION: Self = Self(1);
+    pub const TRANSMISSION: Self = Self(1 << 1);
+    pub const DIFFUSE: Self = Self(1 << 2);
+    pub const GLOSSY: Self = Self(1 << 3);
+    /// Delta distribution, `eval` and `pdf` are zero for it and light sampling can't help.
+    pub const SPECULAR: Self = Self(1 << 4);
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn is_specular(self) -> bool {
+        self.contains(Self::SPECULAR)
+    }
+
+    /// Whether some lobe has a density, i.e. light sampling makes sense.
+    pub fn is_non_specular(self) -> bool {
+        self.0 & (Self::DIFFUSE.0 | Self::GLOSSY.0) != 0
+    }
+}
+
+impl BitOr for LobeFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// Outgoing direction picked by `Material::sample`, in the local shading frame.
+#[derive(Clone)]
+pub struct BsdfSample {
+    pub wo: Vec3A,
+    /// `eval(wi, wo) / pdf`, or the throughput of a specular lobe.
+    pub weight: Vec3A,
+    pub pdf: f32,
+    pub flags: LobeFlags,
+    /// Vertex the path goes on from when it isn't the hit, see `Subsurface`. It samples the lights
+    /// and scatters by its own material, `wo` is the direction the path arrives there with.
+    pub exit: Option<HitRecord>,
+}
+
+/// A BSDF in the local shading frame of a hit, see `HitRecord::world_to_local`.
+/// `wi` points back along the incoming ray and `wo` away from the surface, both normalized.
 pub trait Material: Send + Sync {
-    fn scatter(&self, r_in: &Ray, rec: &HitRecord, attenuation: &mut Vec3A, scattered: &mut Ray) -> bool;
+    /// All lobes this material can sample.
+    fn flags(&self) -> LobeFlags;
+    /// BSDF times |cos(wo)|, zero for specular lobes.
+    fn eval(&self, _rec: &HitRecord, _wi: Vec3A, _wo: Vec3A) -> Vec3A {
+        Vec3A::ZERO
+    }
+    /// Picks `wo` with `uc` choosing the lobe and `u` the direction in it, `None` if the path is absorbed.
+    fn sample(&self, _rec: &HitRecord, _wi: Vec3A, _uc: f32, _u: Vec2) -> Option<BsdfSample> {
+        None
+    }
+    /// Solid angle density of `sample` returning `wo`, zero for specular lobes.
+    fn pdf(&self, _rec: &HitRecord, _wi: Vec3A, _wo: Vec3A) -> f32 {
+        0.
+    }
     fn emitted(&self, _uv: Vec2, _p: Vec3A) -> Vec3A {
         Vec3A::ZERO
     }
+    fn is_emissive(&self) -> bool {
+        false
+    }
+    /// Whether the hit of `r` at `uv` and `p` is masked out, intersection routines then carry on as if they missed.
+    /// Must give the same answer when the same ray is intersected again, e.g. to check it reached a light.
+    fn is_cut_out(&self, _r: &Ray, _uv: Vec2, _p: Vec3A) -> bool {
+        false
+    }
+    /// Medium behind the surface, which paths refracting in travel through until they refract out again.
+    fn interior(&self) -> Option<Arc<Medium>> {
+        None
+    }
 }
 
 pub struct Emission {
@@ -19,13 +96,17 @@ pub struct Emission {
 }
 
 impl Material for Emission {
-    fn scatter(&self, _r_in: &Ray, _rec: &HitRecord, _attenuation: &mut Vec3A, _scattered: &mut Ray) -> bool {
-        false
+    fn flags(&self) -> LobeFlags {
+        LobeFlags::NONE
     }
 
     fn emitted(&self, uv: Vec2, p: Vec3A) -> Vec3A {
         self.emit.value(uv, p)
     }
+
+    fn is_emissive(&self) -> bool {
+        true
+    }
 }
 
 pub struct Diffuse {
@@ -33,28 +114,70 @@ pub struct Diffuse {
 }
 
 impl Material for Diffuse {
-    fn scatter(&self, r_in: &Ray, rec: &HitRecord, attenuation: &mut Vec3A, scattered: &mut Ray) -> bool {
-        let mut scatter_direction = rec.norm + random_in_unit_sphere().normalize();
-        if vec3a_near_zero(scatter_direction) {
-            scatter_direction = rec.norm;
+    fn flags(&self) -> LobeFlags {
+        LobeFlags::DIFFUSE | LobeFlags::REFLECTION
+    }
+
+    fn eval(&self, rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> Vec3A {
+        if !same_hemisphere(wi, wo) {
+            return Vec3A::ZERO;
         }
-        let p = offset_hit_point(rec.p, rec.norm);
-        *scattered = Ray {o: p, d: scatter_direction.normalize(), s: r_in.s};
-        *attenuation = self.albedo.value(rec.uv, rec.p);
-        true
+        self.albedo.value(rec.uv, rec.p) * FRAC_1_PI * wo.z.abs()
+    }
+
+    fn sample(&self, rec: &HitRecord, wi: Vec3A, _uc: f32, u: Vec2) -> Option<BsdfSample> {
+        let mut wo = sample_cosine_hemisphere(u);
+        if wi.z < 0. {
+            wo.z = -wo.z;
+        }
+        if wo.z == 0. {
+            return None;
+        }
+        Some(BsdfSample {
+            wo,
+            weight: self.albedo.value(rec.uv, rec.p),
+            pdf: cosine_hemisphere_pdf(wo.z),
+            flags: self.flags(),
+            exit: None,
+        })
+    }
+
+    fn pdf(&self, _rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> f32 {
+        if same_hemisphere(wi, wo) { cosine_hemisphere_pdf(wo.z) } else { 0. }
     }
 }
 
+/// Same BRDF as `Diffuse`, but sampling the hemisphere uniformly.
 pub struct Lambert {
     pub albedo: Arc<dyn Texture>,
 }
 
 impl Material for Lambert {
-    fn scatter(&self, r_in: &Ray, rec: &HitRecord, attenuation: &mut Vec3A, scattered: &mut Ray) -> bool {
-        let p = offset_hit_point(rec.p, rec.norm);
-        *scattered = Ray {o: p, d: random_on_hemisphere(rec.norm), s: r_in.s};
-        *attenuation = self.albedo.value(rec.uv, rec.p) * 2. * rec.norm.dot(scattered.d);
-        true
+    fn flags(&self) -> LobeFlags {
+        LobeFlags::DIFFUSE | LobeFlags::REFLECTION
+    }
+
+    fn eval(&self, rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> Vec3A {
+        if !same_hemisphere(wi, wo) {
+            return Vec3A::ZERO;
+        }
+        self.albedo.value(rec.uv, rec.p) * FRAC_1_PI * wo.z.abs()
+    }
+
+    fn sample(&self, rec: &HitRecord, wi: Vec3A, _uc: f32, u: Vec2) -> Option<BsdfSample> {
+        let mut wo = sample_uniform_hemisphere(u);
+        if wi.z < 0. {
+            wo.z = -wo.z;
+        }
+        let pdf = self.pdf(rec, wi, wo);
+        if pdf == 0. {
+            return None;
+        }
+        Some(BsdfSample { wo, weight: self.eval(rec, wi, wo) / pdf, pdf, flags: self.flags(), exit: None })
+    }
+
+    fn pdf(&self, _rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> f32 {
+        if same_hemisphere(wi, wo) { 0.5 * FRAC_1_PI } else { 0. }
     }
 }
 
@@ -63,51 +186,311 @@ pub struct Metal {
     pub fuzz: f32,
 }
 
+impl Metal {
+    /// Density of the mirror direction pushed by a point uniform in a ball of radius `fuzz`, normalized to `wo`.
+    /// The ray along `wo` crosses the ball between the roots of `t^2 - 2 t cos + 1 - fuzz^2`,
+    /// and the density sums the volume it goes through, `t^2 dt` over the volume of the ball.
+    fn fuzz_pdf(&self, wi: Vec3A, wo: Vec3A) -> f32 {
+        if !same_hemisphere(wi, wo) {
+            return 0.;
+        }
+        let cos = vec3a(-wi.x, -wi.y, wi.z).dot(wo);
+        let disc = cos * cos - 1. + self.fuzz * self.fuzz;
+        if disc <= 0. {
+            return 0.;
+        }
+        let t_far = cos + disc.sqrt();
+        if t_far <= 0. {
+            return 0.;
+        }
+        let t_near = (cos - disc.sqrt()).max(0.);
+        (t_far.powi(3) - t_near.powi(3)) / (4. * PI * self.fuzz.powi(3))
+    }
+}
+
 impl Material for Metal {
-    fn scatter(&self, r_in: &Ray, rec: &HitRecord, attenuation: &mut Vec3A, scattered: &mut Ray) -> bool {
-        let reflected = reflect(r_in.d, rec.norm) + self.fuzz * random_in_unit_sphere();
-        *scattered = Ray {o: rec.p, d: reflected.normalize(), s: r_in.s};
-        *attenuation = self.albedo;
-        reflected.dot(rec.norm) > 0.
+    // a mirror without fuzz, otherwise a glossy lobe around it
+    fn flags(&self) -> LobeFlags {
+        if self.fuzz > 0. {
+            LobeFlags::GLOSSY | LobeFlags::REFLECTION
+        } else {
+            LobeFlags::SPECULAR | LobeFlags::REFLECTION
+        }
+    }
+
+    fn eval(&self, _rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> Vec3A {
+        if self.fuzz <= 0. {
+            return Vec3A::ZERO;
+        }
+        // samples are weighted by the albedo alone, so the BSDF times the cosine is the albedo times the density
+        self.albedo * self.fuzz_pdf(wi, wo)
+    }
+
+    fn sample(&self, _rec: &HitRecord, wi: Vec3A, uc: f32, u: Vec2) -> Option<BsdfSample> {
+        let in_sphere = sample_uniform_sphere(u) * uc.cbrt();
+        let wo = vec3a(-wi.x, -wi.y, wi.z) + self.fuzz * in_sphere;
+        if !same_hemisphere(wi, wo) {
+            return None;
+        }
+        let wo = wo.normalize();
+        let pdf = if self.fuzz > 0. { self.fuzz_pdf(wi, wo) } else { 1. };
+        if pdf <= 0. {
+            return None;
+        }
+        Some(BsdfSample { wo, weight: self.albedo, pdf, flags: self.flags(), exit: None })
+    }
+
+    fn pdf(&self, _rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> f32 {
+        if self.fuzz > 0. { self.fuzz_pdf(wi, wo) } else { 0. }
     }
 }
 
 pub struct Dielectric {
     pub ior: f32,
+    pub film: Option<ThinFilm>,
+    /// Medium filling the object, e.g. the water of a pool, colouring light with distance.
+    pub medium: Option<Arc<Medium>>,
 }
 
 impl Material for Dielectric {
-    fn scatter(&self, r_in: &Ray, rec: &HitRecord, attenuation: &mut Vec3A, scattered: &mut Ray) -> bool {
-        *attenuation = Vec3A::ONE;
+    fn flags(&self) -> LobeFlags {
+        LobeFlags::SPECULAR | LobeFlags::REFLECTION | LobeFlags::TRANSMISSION
+    }
+
+    fn sample(&self, rec: &HitRecord, wi: Vec3A, uc: f32, _u: Vec2) -> Option<BsdfSample> {
         let ref_idx = if rec.front_face { 1.0 / self.ior } else { self.ior };
-        let cos_theta = -r_in.d.dot(rec.norm).min(1.0);
-        let sin_thera = (1.0 - cos_theta * cos_theta).sqrt();
-        let cannot_refract =  sin_thera * ref_idx > 1.;
-        let rnd_num = RNG.with(|rng| {
-            rng.borrow_mut().gen::<f32>()
-        });
-        let dir = if cannot_refract || reflectance(cos_theta, ref_idx) > rnd_num {
-            reflect(r_in.d, rec.norm)
+        let cos_theta = wi.z.min(1.0);
+        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+        let cannot_refract = sin_theta * ref_idx > 1.;
+        let fresnel = match &self.film {
+            Some(film) => {
+                let (n1, n3) = if rec.front_face { (1., self.ior) } else { (self.ior, 1.) };
+                film.reflectance(rec, cos_theta, n1, Vec3A::splat(n3), Vec3A::ZERO)
+            }
+            None if cannot_refract => Vec3A::ONE,
+            None => Vec3A::splat(reflectance(cos_theta, ref_idx)),
+        };
+        // picking reflection with probability `fresnel` cancels it out of the weight, up to the tint of a film
+        let p = (fresnel.x + fresnel.y + fresnel.z) / 3.;
+        let sample = if uc < p {
+            BsdfSample {
+                wo: vec3a(-wi.x, -wi.y, wi.z),
+                weight: fresnel / p,
+                pdf: p,
+                flags: LobeFlags::SPECULAR | LobeFlags::REFLECTION,
+                exit: None,
+            }
         } else {
-            refract(r_in.d, rec.norm, ref_idx)
-        }.normalize();
-        *scattered = Ray {o: rec.p, d: dir, s: r_in.s};
-        true
+            BsdfSample {
+                wo: refract(-wi, Vec3A::Z, ref_idx).normalize(),
+                weight: (1. - fresnel) / (1. - p),
+                pdf: 1. - p,
+                flags: LobeFlags::SPECULAR | LobeFlags::TRANSMISSION,
+                exit: None,
+            }
+        };
+        Some(sample)
+    }
+
+    fn interior(&self) -> Option<Arc<Medium>> {
+        self.medium.clone()
     }
 }
 
+/// Phase function of an isotropic medium, `eval` has no cosine term.
 pub struct Isotropic {
     pub albedo: Arc<dyn Texture>,
 }
 
 impl Material for Isotropic {
-    fn scatter(&self, r_in: &Ray, rec: &HitRecord, attenuation: &mut Vec3A, scattered: &mut Ray) -> bool {
-        *scattered = Ray {
-            o: rec.p,
-            d: random_on_unit_sphere(),
-            s: r_in.s,
-        };
-        *attenuation = self.albedo.value(rec.uv, rec.p);
-        true
+    fn flags(&self) -> LobeFlags {
+        LobeFlags::DIFFUSE | LobeFlags::REFLECTION | LobeFlags::TRANSMISSION
+    }
+
+    fn eval(&self, rec: &HitRecord, _wi: Vec3A, _wo: Vec3A) -> Vec3A {
+        self.albedo.value(rec.uv, rec.p) / (4. * PI)
+    }
+
+    fn sample(&self, rec: &HitRecord, _wi: Vec3A, _uc: f32, u: Vec2) -> Option<BsdfSample> {
+        Some(BsdfSample {
+            wo: sample_uniform_sphere(u),
+            weight: self.albedo.value(rec.uv, rec.p),
+            pdf: 1. / (4. * PI),
+            flags: self.flags(),
+            exit: None,
+        })
+    }
+
+    fn pdf(&self, _rec: &HitRecord, _wi: Vec3A, _wo: Vec3A) -> f32 {
+        1. / (4. * PI)
+    }
+}
+
+/// One-sample mixture for the combinators below, each of `parts` is a material with the probability
+/// of picking it and its weight in `mix`. Specular samples keep their own throughput,
+/// the others are weighed by the whole mixture so it doesn't matter which part picked them.
+fn sample_mixture<M: Material>(
+    mix: &M, rec: &HitRecord, wi: Vec3A, uc: f32, u: Vec2, parts: [(&dyn Material, f32, f32); 2],
+) -> Option<BsdfSample> {
+    let [a, b] = parts;
+    let (mat, uc, prob, scale) = if uc < b.1 {
+        (b.0, uc / b.1, b.1, b.2)
+    } else {
+        (a.0, (uc - b.1) / a.1, a.1, a.2)
+    };
+    let bs = mat.sample(rec, wi, uc.min(1.), u)?;
+    if bs.flags.is_specular() {
+        return Some(BsdfSample { weight: bs.weight * scale / prob, pdf: bs.pdf * prob, ..bs });
+    }
+    let pdf = mix.pdf(rec, wi, bs.wo);
+    if pdf == 0. {
+        return None;
+    }
+    Some(BsdfSample { weight: mix.eval(rec, wi, bs.wo) / pdf, pdf, ..bs })
+}
+
+/// Blends `a` into `b` by `weight`, e.g. rust over metal with a mask.
+/// Only the first channel of `weight` is used, 0 gives `a` and 1 gives `b`.
+pub struct MixMaterial {
+    pub a: Arc<dyn Material>,
+    pub b: Arc<dyn Material>,
+    pub weight: Arc<dyn Texture>,
+}
+
+impl MixMaterial {
+    fn weight(&self, uv: Vec2, p: Vec3A) -> f32 {
+        self.weight.value(uv, p).x.clamp(0., 1.)
+    }
+}
+
+impl Material for MixMaterial {
+    fn flags(&self) -> LobeFlags {
+        self.a.flags() | self.b.flags()
+    }
+
+    fn eval(&self, rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> Vec3A {
+        let t = self.weight(rec.uv, rec.p);
+        self.a.eval(rec, wi, wo).lerp(self.b.eval(rec, wi, wo), t)
+    }
+
+    fn sample(&self, rec: &HitRecord, wi: Vec3A, uc: f32, u: Vec2) -> Option<BsdfSample> {
+        let t = self.weight(rec.uv, rec.p);
+        sample_mixture(self, rec, wi, uc, u, [(self.a.as_ref(), 1. - t, 1. - t), (self.b.as_ref(), t, t)])
+    }
+
+    fn pdf(&self, rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> f32 {
+        lerp(self.a.pdf(rec, wi, wo), self.b.pdf(rec, wi, wo), self.weight(rec.uv, rec.p))
+    }
+
+    fn emitted(&self, uv: Vec2, p: Vec3A) -> Vec3A {
+        self.a.emitted(uv, p).lerp(self.b.emitted(uv, p), self.weight(uv, p))
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.a.is_emissive() || self.b.is_emissive()
+    }
+}
+
+/// Sum of `a` and `b`, mostly to put an `Emission` on a surface that still scatters.
+/// Nothing keeps the sum of two scattering materials from reflecting more than it receives.
+pub struct AddMaterial {
+    pub a: Arc<dyn Material>,
+    pub b: Arc<dyn Material>,
+}
+
+impl AddMaterial {
+    /// Chance of sampling `b`, never picks a side that doesn't scatter.
+    fn b_prob(&self) -> f32 {
+        match (self.a.flags() == LobeFlags::NONE, self.b.flags() == LobeFlags::NONE) {
+            (true, false) => 1.,
+            (false, true) => 0.,
+            _ => 0.5,
+        }
+    }
+}
+
+impl Material for AddMaterial {
+    fn flags(&self) -> LobeFlags {
+        self.a.flags() | self.b.flags()
+    }
+
+    fn eval(&self, rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> Vec3A {
+        self.a.eval(rec, wi, wo) + self.b.eval(rec, wi, wo)
+    }
+
+    fn sample(&self, rec: &HitRecord, wi: Vec3A, uc: f32, u: Vec2) -> Option<BsdfSample> {
+        let pb = self.b_prob();
+        sample_mixture(self, rec, wi, uc, u, [(self.a.as_ref(), 1. - pb, 1.), (self.b.as_ref(), pb, 1.)])
+    }
+
+    fn pdf(&self, rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> f32 {
+        lerp(self.a.pdf(rec, wi, wo), self.b.pdf(rec, wi, wo), self.b_prob())
+    }
+
+    fn emitted(&self, uv: Vec2, p: Vec3A) -> Vec3A {
+        self.a.emitted(uv, p) + self.b.emitted(uv, p)
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.a.is_emissive() || self.b.is_emissive()
+    }
+}
+
+/// Uniform number in [0, 1) from the bits of the ray and `uv`, murmur3's finalizer after each word.
+fn alpha_hash(r: &Ray, uv: Vec2) -> f32 {
+    let words = r.o.to_array().into_iter().chain(r.d.to_array()).chain(uv.to_array());
+    let h = words.fold(0x9e37_79b9u32, |h, w| {
+        let mut h = (h ^ w.to_bits()).wrapping_mul(0x85eb_ca6b);
+        h ^= h >> 13;
+        h = h.wrapping_mul(0xc2b2_ae35);
+        h ^ (h >> 16)
+    });
+    (h >> 8) as f32 / (1 << 24) as f32
+}
+
+/// Opacity mask over `inner` for leaves and decals, from the first channel of `alpha`.
+/// With a `cutoff` hits below it are ignored, otherwise they are ignored at random with probability `1 - alpha`,
+/// drawn from a hash of the ray and `uv` so that intersecting the same ray again cuts out the same hits.
+pub struct AlphaMask {
+    pub inner: Arc<dyn Material>,
+    pub alpha: Arc<dyn Texture>,
+    pub cutoff: Option<f32>,
+}
+
+impl Material for AlphaMask {
+    fn flags(&self) -> LobeFlags {
+        self.inner.flags()
+    }
+
+    fn eval(&self, rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> Vec3A {
+        self.inner.eval(rec, wi, wo)
+    }
+
+    fn sample(&self, rec: &HitRecord, wi: Vec3A, uc: f32, u: Vec2) -> Option<BsdfSample> {
+        self.inner.sample(rec, wi, uc, u)
+    }
+
+    fn pdf(&self, rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> f32 {
+        self.inner.pdf(rec, wi, wo)
+    }
+
+    fn emitted(&self, uv: Vec2, p: Vec3A) -> Vec3A {
+        self.inner.emitted(uv, p)
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.inner.is_emissive()
+    }
+
+    fn is_cut_out(&self, r: &Ray, uv: Vec2, p: Vec3A) -> bool {
+        let alpha = self.alpha.value(uv, p).x;
+        match self.cutoff {
+            Some(cutoff) => alpha < cutoff,
+            None => alpha < 1. && alpha_hash(r, uv) >= alpha,
+        }
+    }
+
+    fn interior(&self) -> Option<Arc<Medium>> {
+        self.inner.interior()
     }
 }
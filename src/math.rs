@@ -1,3 +1,4 @@
+use std::f32::consts::*;
 use std::mem::transmute;
 
 use glam::*;
@@ -52,6 +53,51 @@ pub fn random_on_hemisphere(norm: Vec3A) -> Vec3A {
     random_in_hemisphere(norm).normalize()
 }
 
+// Sampling in the local shading frame, where the normal is +z.
+
+pub fn same_hemisphere(a: Vec3A, b: Vec3A) -> bool {
+    a.z * b.z > 0.
+}
+
+pub fn sample_uniform_sphere(u: Vec2) -> Vec3A {
+    let z = 1. - 2. * u.x;
+    let r = (1. - z * z).max(0.).sqrt();
+    let (sin_phi, cos_phi) = (2. * PI * u.y).sin_cos();
+    vec3a(r * cos_phi, r * sin_phi, z)
+}
+
+pub fn sample_uniform_hemisphere(u: Vec2) -> Vec3A {
+    let z = u.x;
+    let r = (1. - z * z).max(0.).sqrt();
+    let (sin_phi, cos_phi) = (2. * PI * u.y).sin_cos();
+    vec3a(r * cos_phi, r * sin_phi, z)
+}
+
+/// Shirley and Chiu's concentric mapping of the unit square to the unit disk.
+pub fn sample_concentric_disk(u: Vec2) -> Vec2 {
+    let u = 2. * u - Vec2::ONE;
+    if u == Vec2::ZERO {
+        return Vec2::ZERO;
+    }
+    let (r, theta) = if u.x.abs() > u.y.abs() {
+        (u.x, FRAC_PI_4 * (u.y / u.x))
+    } else {
+        (u.y, FRAC_PI_2 - FRAC_PI_4 * (u.x / u.y))
+    };
+    r * vec2(theta.cos(), theta.sin())
+}
+
+/// Malley's method, projects the disk up onto the hemisphere.
+pub fn sample_cosine_hemisphere(u: Vec2) -> Vec3A {
+    let d = sample_concentric_disk(u);
+    let z = (1. - d.length_squared()).max(0.).sqrt();
+    vec3a(d.x, d.y, z)
+}
+
+pub fn cosine_hemisphere_pdf(cos_theta: f32) -> f32 {
+    cos_theta.abs() * FRAC_1_PI
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct Ray {
     pub o: Vec3A,
@@ -112,6 +158,71 @@ impl AABB {
         true
     }
 
+    /// Slab test with a precomputed `1 / r.d`.
+    pub fn hit_inv(&self, o: Vec3A, inv_d: Vec3A, t_min: f32, t_max: f32) -> bool {
+        let t0 = (self.min - o) * inv_d;
+        let t1 = (self.max - o) * inv_d;
+        let t_near = t0.min(t1).max_element().max(t_min);
+        let t_far = t0.max(t1).min_element().min(t_max);
+        t_near <= t_far
+    }
+
+    /// Part of `t_min..t_max` along `r` inside the box, if any.
+    pub fn clip(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<(f32, f32)> {
+        let inv_d = r.d.recip();
+        let t0 = (self.min - r.o) * inv_d;
+        let t1 = (self.max - r.o) * inv_d;
+        let t_near = t0.min(t1).max_element().max(t_min);
+        let t_far = t0.max(t1).min_element().min(t_max);
+        if t_near < t_far { Some((t_near, t_far)) } else { None }
+    }
+
+    pub fn empty() -> Self {
+        Self {
+            min: Vec3A::splat(f32::INFINITY),
+            max: Vec3A::splat(f32::NEG_INFINITY),
+        }
+    }
+
+    pub fn grow(&self, p: Vec3A) -> Self {
+        Self {
+            min: self.min.min(p),
+            max: self.max.max(p),
+        }
+    }
+
+    pub fn centroid(&self) -> Vec3A {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let d = self.max - self.min;
+        if d.min_element() < 0. {
+            return 0.;
+        }
+        2. * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    pub fn largest_axis(&self) -> usize {
+        let d = self.max - self.min;
+        if d.x > d.y && d.x > d.z { 0 } else if d.y > d.z { 1 } else { 2 }
+    }
+
+    /// Box around the transformed box, same as transforming all 8 corners.
+    /// James Arvo, "Transforming Axis-Aligned Bounding Boxes", Graphics Gems 1990
+    pub fn transform(&self, m: &Affine3A) -> Self {
+        let mut min = m.translation;
+        let mut max = m.translation;
+        for j in 0..3 {
+            let col = m.matrix3.col(j);
+            let a = col * self.min[j];
+            let b = col * self.max[j];
+            min += a.min(b);
+            max += a.max(b);
+        }
+        Self { min, max }
+    }
+
     pub fn surround(&self, rhs: Self) -> Self {
         let min = vec3a(
             self.min.x.min(rhs.min.x),
@@ -151,6 +262,21 @@ pub fn offset_hit_point(p: Vec3A, n: Vec3A) -> Vec3A {
     vec3a(x, y, z)
 }
 
+/// Eric Veach, "Robust Monte Carlo Methods for Light Transport Simulation", 9.2.4
+pub fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let (a, b) = (pdf * pdf, other_pdf * other_pdf);
+    if a + b > 0. { a / (a + b) } else { 0. }
+}
+
 pub fn lerp(from: f32, to: f32, s: f32) -> f32 {
     from + (to - from) * s
 }
+
+/// Builds an orthonormal tangent for `n`.
+/// https://graphics.pixar.com/library/OrthonormalB/paper.pdf
+pub fn coordinate_system(n: Vec3A) -> Vec3A {
+    let sign = 1f32.copysign(n.z);
+    let a = -1. / (sign + n.z);
+    let b = n.x * n.y * a;
+    vec3a(1. + sign * n.x * n.x * a, sign * b, -sign * n.x)
+}
@@ -0,0 +1,375 @@
+use std::sync::Arc;
+
+use glam::*;
+
+use crate::bvh::*;
+use crate::hitable::{area_pdf, random_pair, HitRecord, Hitable};
+use crate::material::Material;
+use crate::math::*;
+
+const MESH_LEAF_SIZE: usize = 4;
+
+/// Ray transformed into the shear space of the watertight test.
+/// Sven Woop, Carsten Benthin, Ingo Wald, "Watertight Ray/Triangle Intersection"
+/// https://jcgt.org/published/0002/01/05/paper.pdf
+struct WatertightRay {
+    o: Vec3A,
+    kx: usize,
+    ky: usize,
+    kz: usize,
+    s: Vec3A,
+}
+
+impl WatertightRay {
+    fn new(r: &Ray) -> Self {
+        let d = r.d.abs();
+        let kz = if d.x > d.y && d.x > d.z { 0 } else if d.y > d.z { 1 } else { 2 };
+        let mut kx = (kz + 1) % 3;
+        let mut ky = (kx + 1) % 3;
+        if r.d[kz] < 0. {
+            std::mem::swap(&mut kx, &mut ky);
+        }
+        let s = vec3a(r.d[kx] / r.d[kz], r.d[ky] / r.d[kz], 1. / r.d[kz]);
+        Self { o: r.o, kx, ky, kz, s }
+    }
+
+    /// Returns `(t, b0, b1, b2)` where `b*` are the barycentric weights of `p0`, `p1` and `p2`.
+    fn intersect(&self, p0: Vec3A, p1: Vec3A, p2: Vec3A, t_min: f32, t_max: f32) -> Option<(f32, f32, f32, f32)> {
+        let a = p0 - self.o;
+        let b = p1 - self.o;
+        let c = p2 - self.o;
+
+        let ax = a[self.kx] - self.s.x * a[self.kz];
+        let ay = a[self.ky] - self.s.y * a[self.kz];
+        let bx = b[self.kx] - self.s.x * b[self.kz];
+        let by = b[self.ky] - self.s.y * b[self.kz];
+        let cx = c[self.kx] - self.s.x * c[self.kz];
+        let cy = c[self.ky] - self.s.y * c[self.kz];
+
+        let mut u = cx * by - cy * bx;
+        let mut v = ax * cy - ay * cx;
+        let mut w = bx * ay - by * ax;
+        // fall back to double precision on edges
+        if u == 0. || v == 0. || w == 0. {
+            u = (cx as f64 * by as f64 - cy as f64 * bx as f64) as f32;
+            v = (ax as f64 * cy as f64 - ay as f64 * cx as f64) as f32;
+            w = (bx as f64 * ay as f64 - by as f64 * ax as f64) as f32;
+        }
+        if (u < 0. || v < 0. || w < 0.) && (u > 0. || v > 0. || w > 0.) {
+            return None;
+        }
+        let det = u + v + w;
+        if det == 0. {
+            return None;
+        }
+
+        let az = self.s.z * a[self.kz];
+        let bz = self.s.z * b[self.kz];
+        let cz = self.s.z * c[self.kz];
+        let t = (u * az + v * bz + w * cz) / det;
+        if t < t_min || t > t_max {
+            return None;
+        }
+        Some((t, u / det, v / det, w / det))
+    }
+}
+
+/// dp/du and dp/dv of a triangle, dp/du is also used as the shading tangent.
+fn triangle_uv_derivatives(p: [Vec3A; 3], uv: [Vec2; 3]) -> Option<(Vec3A, Vec3A)> {
+    let dp02 = p[0] - p[2];
+    let dp12 = p[1] - p[2];
+    let duv02 = uv[0] - uv[2];
+    let duv12 = uv[1] - uv[2];
+    let det = duv02.x * duv12.y - duv02.y * duv12.x;
+    if det.abs() < 1e-12 {
+        return None;
+    }
+    let dpdu = (duv12.y * dp02 - duv02.y * dp12) / det;
+    let dpdv = (duv02.x * dp12 - duv12.x * dp02) / det;
+    if vec3a_near_zero(dpdu) {
+        None
+    } else {
+        Some((dpdu, dpdv))
+    }
+}
+
+/// Uniformly distributed point of a triangle.
+fn sample_triangle(p: [Vec3A; 3], u1: f32, u2: f32) -> Vec3A {
+    let su = u1.sqrt();
+    (1. - su) * p[0] + su * (1. - u2) * p[1] + su * u2 * p[2]
+}
+
+fn triangle_area(p: [Vec3A; 3]) -> f32 {
+    0.5 * (p[1] - p[0]).cross(p[2] - p[0]).length()
+}
+
+fn triangle_aabb(p: [Vec3A; 3]) -> AABB {
+    let b = AABB::empty().grow(p[0]).grow(p[1]).grow(p[2]);
+    // pad axis-aligned triangles like the rects do
+    AABB { min: b.min - 0.0001, max: b.max + 0.0001 }
+}
+
+/// Gram-Schmidt `tang` against `norm`, falling back to an arbitrary tangent.
+fn orthogonal_tangent(tang: Vec3A, norm: Vec3A) -> Vec3A {
+    let t = tang - norm * norm.dot(tang);
+    if t.length_squared() > 1e-12 {
+        t.normalize()
+    } else {
+        coordinate_system(norm)
+    }
+}
+
+/// Fills `rec` from a barycentric hit.
+/// `ng` is the unnormalized geometric normal, `n` the interpolated shading normal if any.
+#[allow(clippy::too_many_arguments)]
+fn fill_record(
+    r: &Ray,
+    t: f32,
+    ng: Vec3A,
+    n: Option<Vec3A>,
+    tang: Vec3A,
+    uv: Vec2,
+    mat: &Arc<dyn Material>,
+    rec: &mut HitRecord,
+) {
+    let mut ng = ng.normalize();
+    let norm = match n {
+        Some(n) if !vec3a_near_zero(n) => {
+            let n = n.normalize();
+            // orient the geometric normal to the side the shading normals point to
+            if ng.dot(n) < 0. {
+                ng = -ng;
+            }
+            n
+        },
+        _ => ng,
+    };
+    rec.t = t;
+    rec.p = r.at(t);
+    rec.uv = uv;
+    rec.front_face = r.d.dot(ng) < 0.;
+    rec.norm = if rec.front_face { norm } else { -norm };
+    rec.geom_norm = if rec.front_face { ng } else { -ng };
+    rec.tang = orthogonal_tangent(tang, rec.norm);
+    rec.mat = Some(mat.clone());
+}
+
+#[derive(Clone)]
+pub struct Triangle {
+    pub p: [Vec3A; 3],
+    pub n: Option<[Vec3A; 3]>,
+    pub uv: Option<[Vec2; 3]>,
+    pub mat: Arc<dyn Material>,
+}
+
+impl Hitable for Triangle {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32, rec: &mut HitRecord) -> bool {
+        let wr = WatertightRay::new(r);
+        let (t, b0, b1, b2) = match wr.intersect(self.p[0], self.p[1], self.p[2], t_min, t_max) {
+            Some(v) => v,
+            None => return false,
+        };
+        let uvs = self.uv.unwrap_or([Vec2::ZERO, Vec2::X, Vec2::Y]);
+        let uv = b0 * uvs[0] + b1 * uvs[1] + b2 * uvs[2];
+        if self.mat.is_cut_out(r, uv, r.at(t)) {
+            return false;
+        }
+        let ng = (self.p[1] - self.p[0]).cross(self.p[2] - self.p[0]);
+        let n = self.n.map(|n| b0 * n[0] + b1 * n[1] + b2 * n[2]);
+        let (dpdu, dpdv) = triangle_uv_derivatives(self.p, uvs).unwrap_or((Vec3A::ZERO, Vec3A::ZERO));
+        let tang = if dpdu == Vec3A::ZERO { self.p[1] - self.p[0] } else { dpdu };
+        fill_record(r, t, ng, n, tang, uv, &self.mat, rec);
+        (rec.dpdu, rec.dpdv) = (dpdu, dpdv);
+        true
+    }
+
+    fn bbox(&self, aabb: &mut AABB) -> bool {
+        *aabb = triangle_aabb(self.p);
+        true
+    }
+
+    fn memo(&self) -> String {
+        "Triangle".into()
+    }
+
+    fn is_light(&self) -> bool {
+        self.mat.is_emissive()
+    }
+
+    fn sample(&self, o: Vec3A) -> Option<Vec3A> {
+        let (u1, u2) = random_pair();
+        Some(sample_triangle(self.p, u1, u2) - o)
+    }
+
+    fn pdf(&self, o: Vec3A, d: Vec3A) -> f32 {
+        area_pdf(self, triangle_area(self.p), o, d)
+    }
+
+    fn transformed_bbox(&self, xform: &Affine3A, aabb: &mut AABB) -> bool {
+        *aabb = triangle_aabb(self.p.map(|p| xform.transform_point3a(p)));
+        true
+    }
+}
+
+/// Indexed triangle mesh sharing one material, with its own BVH over the triangles.
+pub struct TriangleMesh {
+    positions: Vec<Vec3A>,
+    normals: Vec<Vec3A>,
+    tangents: Vec<Vec3A>,
+    uvs: Vec<Vec2>,
+    indices: Vec<[u32; 3]>,
+    bvh: LinearBvh,
+    /// Running sum of the triangle areas to sample emissive meshes by, empty for the others.
+    area_cdf: Vec<f32>,
+    mat: Arc<dyn Material>,
+    name: String,
+}
+
+impl TriangleMesh {
+    /// `normals` and `uvs` are either empty or indexed like `positions`.
+    pub fn new(
+        positions: Vec<Vec3A>,
+        normals: Vec<Vec3A>,
+        uvs: Vec<Vec2>,
+        indices: Vec<[u32; 3]>,
+        mat: Arc<dyn Material>,
+        name: String,
+    ) -> Self {
+        assert!(normals.is_empty() || normals.len() == positions.len());
+        assert!(uvs.is_empty() || uvs.len() == positions.len());
+        assert!(indices.iter().flatten().all(|&i| (i as usize) < positions.len()));
+
+        let mut tangents = vec![Vec3A::ZERO; if uvs.is_empty() { 0 } else { positions.len() }];
+        if !uvs.is_empty() {
+            for tri in &indices {
+                let p = tri.map(|i| positions[i as usize]);
+                let uv = tri.map(|i| uvs[i as usize]);
+                if let Some((dpdu, _)) = triangle_uv_derivatives(p, uv) {
+                    for &i in tri {
+                        tangents[i as usize] += dpdu;
+                    }
+                }
+            }
+        }
+
+        let prims = indices.iter().enumerate()
+            .map(|(i, tri)| BuildPrim::new(i, triangle_aabb(tri.map(|i| positions[i as usize]))))
+            .collect();
+        let options = BvhOptions { split: BvhSplit::Sah { bins: 16 }, max_leaf_size: MESH_LEAF_SIZE };
+        let (bvh, _) = LinearBvh::new(prims, options);
+
+        let mut area_cdf = Vec::new();
+        if mat.is_emissive() {
+            let mut sum = 0.;
+            area_cdf = indices.iter().map(|tri| {
+                sum += triangle_area(tri.map(|i| positions[i as usize]));
+                sum
+            }).collect();
+        }
+
+        Self { positions, normals, tangents, uvs, indices, bvh, area_cdf, mat, name }
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.indices.len()
+    }
+
+    fn uv(&self, prim: usize, b: Vec3A) -> Vec2 {
+        let [i0, i1, i2] = self.indices[prim].map(|i| i as usize);
+        if self.uvs.is_empty() {
+            vec2(b.y, b.z)
+        } else {
+            b.x * self.uvs[i0] + b.y * self.uvs[i1] + b.z * self.uvs[i2]
+        }
+    }
+
+    fn shade(&self, r: &Ray, prim: usize, t: f32, b: Vec3A, rec: &mut HitRecord) {
+        let tri = self.indices[prim];
+        let [i0, i1, i2] = tri.map(|i| i as usize);
+        let p = [self.positions[i0], self.positions[i1], self.positions[i2]];
+        let ng = (p[1] - p[0]).cross(p[2] - p[0]);
+
+        let uv = self.uv(prim, b);
+        let uvs = if self.uvs.is_empty() { [Vec2::ZERO, Vec2::X, Vec2::Y] } else { tri.map(|i| self.uvs[i as usize]) };
+        let (dpdu, dpdv) = triangle_uv_derivatives(p, uvs).unwrap_or((Vec3A::ZERO, Vec3A::ZERO));
+        let n = if self.normals.is_empty() {
+            None
+        } else {
+            Some(b.x * self.normals[i0] + b.y * self.normals[i1] + b.z * self.normals[i2])
+        };
+        let tang = if self.tangents.is_empty() {
+            p[1] - p[0]
+        } else {
+            b.x * self.tangents[i0] + b.y * self.tangents[i1] + b.z * self.tangents[i2]
+        };
+        fill_record(r, t, ng, n, tang, uv, &self.mat, rec);
+        (rec.dpdu, rec.dpdv) = (dpdu, dpdv);
+    }
+}
+
+impl Hitable for TriangleMesh {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32, rec: &mut HitRecord) -> bool {
+        let wr = WatertightRay::new(r);
+        let mut found = None;
+        self.bvh.traverse(r, t_min, t_max, |prim, t_max| {
+            let [i0, i1, i2] = self.indices[prim];
+            let p0 = self.positions[i0 as usize];
+            let p1 = self.positions[i1 as usize];
+            let p2 = self.positions[i2 as usize];
+            let (t, b0, b1, b2) = wr.intersect(p0, p1, p2, t_min, t_max)?;
+            let b = vec3a(b0, b1, b2);
+            if self.mat.is_cut_out(r, self.uv(prim, b), r.at(t)) {
+                return None;
+            }
+            found = Some((prim, t, b));
+            Some(t)
+        });
+
+        match found {
+            Some((prim, t, b)) => {
+                self.shade(r, prim, t, b, rec);
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn bbox(&self, aabb: &mut AABB) -> bool {
+        match self.bvh.bounds() {
+            Some(b) => {
+                *aabb = b;
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn memo(&self) -> String {
+        self.name.clone()
+    }
+
+    fn is_light(&self) -> bool {
+        self.area_cdf.last().is_some_and(|&area| area > 0.)
+    }
+
+    /// Picks a triangle by its area and a point uniformly on it.
+    fn sample(&self, o: Vec3A) -> Option<Vec3A> {
+        let area = *self.area_cdf.last()?;
+        let (u1, u2) = random_pair();
+        let x = u1 * area;
+        let prim = self.area_cdf.partition_point(|&c| c <= x).min(self.indices.len() - 1);
+        let lo = if prim == 0 { 0. } else { self.area_cdf[prim - 1] };
+        let width = self.area_cdf[prim] - lo;
+        // reuse what is left of `u1` inside the chosen triangle
+        let u1 = if width > 0. { ((x - lo) / width).clamp(0., 1.) } else { 0. };
+        let p = self.indices[prim].map(|i| self.positions[i as usize]);
+        Some(sample_triangle(p, u1, u2) - o)
+    }
+
+    fn pdf(&self, o: Vec3A, d: Vec3A) -> f32 {
+        match self.area_cdf.last() {
+            Some(&area) if area > 0. => area_pdf(self, area, o, d),
+            _ => 0.,
+        }
+    }
+}
@@ -0,0 +1,301 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use glam::*;
+
+use crate::hitable::HitableList;
+use crate::material::*;
+use crate::mesh::TriangleMesh;
+use crate::pbr::RoughPlastic;
+use crate::texture::*;
+
+#[derive(Debug)]
+pub enum ObjError {
+    Io(PathBuf, std::io::Error),
+    Image(PathBuf, image::ImageError),
+    Parse { path: PathBuf, line: usize, msg: String },
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ObjError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            ObjError::Image(path, e) => write!(f, "{}: {}", path.display(), e),
+            ObjError::Parse { path, line, msg } => write!(f, "{}:{}: {}", path.display(), line, msg),
+        }
+    }
+}
+
+impl std::error::Error for ObjError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ObjError::Io(_, e) => Some(e),
+            ObjError::Image(_, e) => Some(e),
+            ObjError::Parse { .. } => None,
+        }
+    }
+}
+
+/// The subset of an MTL material we map onto our own materials.
+#[derive(Clone)]
+struct MtlDesc {
+    kd: Vec3A,
+    ks: Vec3A,
+    ns: f32,
+    ni: f32,
+    d: f32,
+    map_kd: Option<PathBuf>,
+}
+
+impl Default for MtlDesc {
+    fn default() -> Self {
+        Self {
+            kd: Vec3A::splat(0.8),
+            ks: Vec3A::ZERO,
+            ns: 0.,
+            ni: 1.5,
+            d: 1.,
+            map_kd: None,
+        }
+    }
+}
+
+impl MtlDesc {
+    /// Phong exponent to the perceptual roughness used by `pbr.rs`.
+    fn roughness(&self) -> f32 {
+        let alpha = (2. / (self.ns.max(0.) + 2.)).sqrt();
+        alpha.sqrt().clamp(0.01, 1.)
+    }
+
+    fn build(&self) -> Result<Arc<dyn Material>, ObjError> {
+        if self.d < 1. {
+            return Ok(Arc::new(Dielectric { ior: self.ni, film: None, medium: None }));
+        }
+        let albedo: Arc<dyn Texture> = match &self.map_kd {
+            Some(path) => {
+                let tex = ImageTex::load(path).map_err(|e| ObjError::Image(path.clone(), e))?;
+                Arc::new(tex)
+            },
+            None => Arc::new(ConstantTex { col: self.kd }),
+        };
+        let has_diffuse = self.map_kd.is_some() || self.kd.max_element() > 0.;
+        let has_specular = self.ks.max_element() > 0.;
+        let mat: Arc<dyn Material> = match (has_diffuse, has_specular) {
+            (true, true) => Arc::new(RoughPlastic {
+                spec_color: Arc::new(ConstantTex { col: self.ks }),
+                diff_color: albedo,
+                roughness: self.roughness(),
+                eta: self.ni,
+                film: None,
+            }),
+            (false, true) => Arc::new(Metal { albedo: self.ks, fuzz: self.roughness() }),
+            _ => Arc::new(Diffuse { albedo }),
+        };
+        Ok(mat)
+    }
+}
+
+fn parse_error(path: &Path, line: usize, msg: impl Into<String>) -> ObjError {
+    ObjError::Parse { path: path.to_path_buf(), line, msg: msg.into() }
+}
+
+fn parse_floats<const N: usize>(path: &Path, line: usize, args: &[&str]) -> Result<[f32; N], ObjError> {
+    if args.len() < N {
+        return Err(parse_error(path, line, format!("expected {} numbers, found {}", N, args.len())));
+    }
+    let mut v = [0.; N];
+    for (i, a) in args.iter().take(N).enumerate() {
+        v[i] = a.parse().map_err(|_| parse_error(path, line, format!("invalid number '{}'", a)))?;
+    }
+    Ok(v)
+}
+
+fn load_mtl(path: &Path) -> Result<HashMap<String, MtlDesc>, ObjError> {
+    let text = fs::read_to_string(path).map_err(|e| ObjError::Io(path.to_path_buf(), e))?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut mtls = HashMap::new();
+    let mut current: Option<(String, MtlDesc)> = None;
+
+    for (i, line) in text.lines().enumerate() {
+        let line_no = i + 1;
+        let mut tokens = line.split_whitespace();
+        let key = match tokens.next() {
+            Some(k) if !k.starts_with('#') => k,
+            _ => continue,
+        };
+        let args: Vec<&str> = tokens.collect();
+        if key == "newmtl" {
+            if let Some((name, desc)) = current.take() {
+                mtls.insert(name, desc);
+            }
+            let name = args.join(" ");
+            if name.is_empty() {
+                return Err(parse_error(path, line_no, "newmtl without a name"));
+            }
+            current = Some((name, MtlDesc::default()));
+            continue;
+        }
+        let desc = match current.as_mut() {
+            Some((_, desc)) => desc,
+            None => return Err(parse_error(path, line_no, format!("'{}' before newmtl", key))),
+        };
+        match key {
+            "Kd" => desc.kd = Vec3A::from(parse_floats::<3>(path, line_no, &args)?),
+            "Ks" => desc.ks = Vec3A::from(parse_floats::<3>(path, line_no, &args)?),
+            "Ns" => desc.ns = parse_floats::<1>(path, line_no, &args)?[0],
+            "Ni" => desc.ni = parse_floats::<1>(path, line_no, &args)?[0],
+            "d" => desc.d = parse_floats::<1>(path, line_no, &args)?[0],
+            "Tr" => desc.d = 1. - parse_floats::<1>(path, line_no, &args)?[0],
+            "map_Kd" => {
+                // options such as "-bm 1" come before the file name
+                let file = args.last().ok_or_else(|| parse_error(path, line_no, "map_Kd without a file"))?;
+                desc.map_kd = Some(dir.join(file));
+            },
+            _ => {},
+        }
+    }
+    if let Some((name, desc)) = current.take() {
+        mtls.insert(name, desc);
+    }
+    Ok(mtls)
+}
+
+/// Resolves a 1-based (or negative, relative) OBJ index.
+fn resolve_index(path: &Path, line: usize, s: &str, len: usize) -> Result<usize, ObjError> {
+    let i: i64 = s.parse().map_err(|_| parse_error(path, line, format!("invalid index '{}'", s)))?;
+    let resolved = if i > 0 { i - 1 } else { len as i64 + i };
+    if i == 0 || resolved < 0 || resolved >= len as i64 {
+        return Err(parse_error(path, line, format!("index {} out of range", i)));
+    }
+    Ok(resolved as usize)
+}
+
+/// Triangles of one object/material pair, with vertices deduplicated per `v/vt/vn` triple.
+#[derive(Default)]
+struct MeshBuilder {
+    positions: Vec<Vec3A>,
+    normals: Vec<Vec3A>,
+    uvs: Vec<Vec2>,
+    indices: Vec<[u32; 3]>,
+    remap: HashMap<(usize, Option<usize>, Option<usize>), u32>,
+    has_normals: bool,
+    has_uvs: bool,
+}
+
+/// Index of `name` in `names`, adding it the first time it is seen.
+fn intern(names: &mut Vec<String>, ids: &mut HashMap<String, usize>, name: String) -> usize {
+    *ids.entry(name).or_insert_with_key(|name| {
+        names.push(name.clone());
+        names.len() - 1
+    })
+}
+
+/// Loads a Wavefront OBJ file together with its MTL libraries.
+/// Every object/group and material pair becomes one `TriangleMesh`.
+pub fn load_obj<P: AsRef<Path>>(path: P) -> Result<HitableList, ObjError> {
+    let path = path.as_ref();
+    let text = fs::read_to_string(path).map_err(|e| ObjError::Io(path.to_path_buf(), e))?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+    let mut positions: Vec<Vec3A> = Vec::new();
+    let mut normals: Vec<Vec3A> = Vec::new();
+    let mut uvs: Vec<Vec2> = Vec::new();
+    let mut mtls: HashMap<String, MtlDesc> = HashMap::new();
+
+    // groups and materials are interned so faces are binned without allocating
+    let (mut groups, mut group_ids) = (Vec::new(), HashMap::new());
+    let (mut mtl_names, mut mtl_ids) = (Vec::new(), HashMap::new());
+    let mut group = intern(&mut groups, &mut group_ids, String::from("default"));
+    let mut mtl = intern(&mut mtl_names, &mut mtl_ids, String::new());
+    let mut builders: HashMap<(usize, usize), MeshBuilder> = HashMap::new();
+    // meshes come out in the order they first appear
+    let mut order: Vec<(usize, usize)> = Vec::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let line_no = i + 1;
+        let mut tokens = line.split_whitespace();
+        let key = match tokens.next() {
+            Some(k) if !k.starts_with('#') => k,
+            _ => continue,
+        };
+        let args: Vec<&str> = tokens.collect();
+        match key {
+            "v" => positions.push(Vec3A::from(parse_floats::<3>(path, line_no, &args)?)),
+            "vn" => normals.push(Vec3A::from(parse_floats::<3>(path, line_no, &args)?)),
+            "vt" => uvs.push(Vec2::from(parse_floats::<2>(path, line_no, &args)?)),
+            "o" | "g" => group = intern(&mut groups, &mut group_ids, args.join(" ")),
+            "usemtl" => mtl = intern(&mut mtl_names, &mut mtl_ids, args.join(" ")),
+            "mtllib" => {
+                for file in &args {
+                    mtls.extend(load_mtl(&dir.join(file))?);
+                }
+            },
+            "f" => {
+                if args.len() < 3 {
+                    return Err(parse_error(path, line_no, "face with less than 3 vertices"));
+                }
+                let builder = builders.entry((group, mtl)).or_insert_with(|| {
+                    order.push((group, mtl));
+                    MeshBuilder::default()
+                });
+
+                let mut face = Vec::with_capacity(args.len());
+                for vert in &args {
+                    let mut parts = vert.split('/');
+                    let v = resolve_index(path, line_no, parts.next().unwrap_or(""), positions.len())?;
+                    let vt = match parts.next() {
+                        Some(s) if !s.is_empty() => Some(resolve_index(path, line_no, s, uvs.len())?),
+                        _ => None,
+                    };
+                    let vn = match parts.next() {
+                        Some(s) if !s.is_empty() => Some(resolve_index(path, line_no, s, normals.len())?),
+                        _ => None,
+                    };
+                    let next = builder.positions.len() as u32;
+                    let index = *builder.remap.entry((v, vt, vn)).or_insert(next);
+                    if index == next {
+                        builder.positions.push(positions[v]);
+                        builder.uvs.push(vt.map_or(Vec2::ZERO, |i| uvs[i]));
+                        builder.normals.push(vn.map_or(Vec3A::ZERO, |i| normals[i]));
+                        builder.has_uvs |= vt.is_some();
+                        builder.has_normals |= vn.is_some();
+                    }
+                    face.push(index);
+                }
+                // fan triangulation of convex polygons
+                for k in 1..face.len() - 1 {
+                    builder.indices.push([face[0], face[k], face[k + 1]]);
+                }
+            },
+            _ => {},
+        }
+    }
+
+    let mut materials: HashMap<String, Arc<dyn Material>> = HashMap::new();
+    let mut world: HitableList = Vec::new();
+    for key in order {
+        let builder = builders.remove(&key).unwrap();
+        let (group, mtl_name) = (&groups[key.0], &mtl_names[key.1]);
+        let mat = match materials.get(mtl_name) {
+            Some(mat) => mat.clone(),
+            None => {
+                let mat = mtls.get(mtl_name).cloned().unwrap_or_default().build()?;
+                materials.insert(mtl_name.clone(), mat.clone());
+                mat
+            },
+        };
+        let MeshBuilder { positions, normals, uvs, indices, has_normals, has_uvs, .. } = builder;
+        world.push(Arc::new(TriangleMesh::new(
+            positions,
+            if has_normals { normals } else { Vec::new() },
+            if has_uvs { uvs } else { Vec::new() },
+            indices,
+            mat,
+            format!("{}/{}", group, mtl_name),
+        )));
+    }
+    Ok(world)
+}
@@ -1,13 +1,35 @@
 use std::f32::consts::*;
+use std::ops::{Add, Div, Mul, Sub};
 use std::sync::Arc;
 
 use glam::*;
 
 use crate::hitable::HitRecord;
-use crate::material::Material;
+use crate::material::*;
 use crate::math::*;
-use crate::texture::Texture;
+use crate::texture::{ConstantTex, Texture};
+use crate::lib::RNG;
+use rand::Rng;
 
+/// Uniform hemisphere sampling, for the materials here without importance sampling.
+fn sample_hemisphere<M: Material>(mat: &M, rec: &HitRecord, wi: Vec3A, u: Vec2) -> Option<BsdfSample> {
+    let wo = sample_uniform_hemisphere(u);
+    let pdf = hemisphere_pdf(wi, wo);
+    if pdf == 0. {
+        return None;
+    }
+    Some(BsdfSample { wo, weight: mat.eval(rec, wi, wo) / pdf, pdf, flags: mat.flags(), exit: None })
+}
+
+fn hemisphere_pdf(wi: Vec3A, wo: Vec3A) -> f32 {
+    if same_hemisphere(wi, wo) { 0.5 * FRAC_1_PI } else { 0. }
+}
+
+/// Turns the tangent around the normal by `rot`, like `HitRecord::world_to_local_with_rot`.
+fn rotate_tangent(v: Vec3A, rot: f32) -> Vec3A {
+    let (sin, cos) = rot.sin_cos();
+    vec3a(cos * v.x - sin * v.y, sin * v.x + cos * v.y, v.z)
+}
 
 pub struct OrenNayar {
     pub albedo: Arc<dyn Texture>,
@@ -15,11 +37,20 @@ pub struct OrenNayar {
 }
 
 impl Material for OrenNayar {
-    fn scatter(&self, r_in: &Ray, rec: &HitRecord, attenuation: &mut Vec3A, scattered: &mut Ray) -> bool {
-        let p = offset_hit_point(rec.p, rec.norm);
-        let dir_o = random_on_hemisphere(rec.norm);
-        let cos_i = rec.norm.dot(r_in.d).abs();
-        let cos_o = rec.norm.dot(dir_o);
+    fn flags(&self) -> LobeFlags {
+        LobeFlags::DIFFUSE | LobeFlags::REFLECTION
+    }
+
+    fn sample(&self, rec: &HitRecord, wi: Vec3A, _uc: f32, u: Vec2) -> Option<BsdfSample> {
+        sample_hemisphere(self, rec, wi, u)
+    }
+
+    fn eval(&self, rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> Vec3A {
+        let cos_i = wi.z;
+        let cos_o = wo.z;
+        if cos_o <= 0. {
+            return Vec3A::ZERO;
+        }
         let sin_i = (1. - cos_i * cos_i).sqrt();
         let sin_o = (1. - cos_o * cos_o).sqrt();
         let max_cos = (cos_i * cos_o + sin_i * sin_o).max(0.);
@@ -35,9 +66,11 @@ impl Material for OrenNayar {
         };
         let w = a + b * max_cos * sin_alpha * tan_beta;
 
-        *scattered = Ray {o: p, d: dir_o, s: r_in.s};
-        *attenuation = self.albedo.value(rec.uv, rec.p) * w * 2. * cos_o;
-        true
+        self.albedo.value(rec.uv, rec.p) * w * FRAC_1_PI * cos_o
+    }
+
+    fn pdf(&self, _rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> f32 {
+        hemisphere_pdf(wi, wo)
     }
 }
 
@@ -48,13 +81,22 @@ pub struct BurleyDiffuse {
 }
 
 impl Material for BurleyDiffuse {
-    fn scatter(&self, r_in: &Ray, rec: &HitRecord, attenuation: &mut Vec3A, scattered: &mut Ray) -> bool {
-        let p = offset_hit_point(rec.p, rec.norm);
-        let dir_o = random_on_hemisphere(rec.norm);
-        let n_dot_i = rec.norm.dot(-r_in.d);
-        let n_dot_o = rec.norm.dot(dir_o);
-        let h = (dir_o - r_in.d).normalize();
-        let h_dot_o = h.dot(dir_o);
+    fn flags(&self) -> LobeFlags {
+        LobeFlags::DIFFUSE | LobeFlags::REFLECTION
+    }
+
+    fn sample(&self, rec: &HitRecord, wi: Vec3A, _uc: f32, u: Vec2) -> Option<BsdfSample> {
+        sample_hemisphere(self, rec, wi, u)
+    }
+
+    fn eval(&self, rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> Vec3A {
+        let n_dot_i = wi.z;
+        let n_dot_o = wo.z;
+        if n_dot_o <= 0. {
+            return Vec3A::ZERO;
+        }
+        let h = (wi + wo).normalize();
+        let h_dot_o = h.dot(wo);
 
         let fl = schlick_fresnel(n_dot_o);
         let fv = schlick_fresnel(n_dot_i);
@@ -62,9 +104,11 @@ impl Material for BurleyDiffuse {
         let fd90 = 0.5 + 2. * h_dot_o * h_dot_o * self.roughness;
         let fd = lerp(1.0, fd90, fl) * lerp(1.0, fd90, fv);
 
-        *scattered = Ray {o: p, d: dir_o, s: r_in.s};
-        *attenuation = self.albedo.value(rec.uv, rec.p) * fd * 2. * n_dot_o;
-        true
+        self.albedo.value(rec.uv, rec.p) * fd * FRAC_1_PI * n_dot_o
+    }
+
+    fn pdf(&self, _rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> f32 {
+        hemisphere_pdf(wi, wo)
     }
 }
 
@@ -88,6 +132,59 @@ fn gtr2_ansio(h_local: Vec3A, ax: f32, ay: f32) -> f32 {
     1. / (PI * ax * ay * ((h_local.x / ax).powi(2) + (h_local.y / ay).powi(2) + h_local.z * h_local.z).powi(2))
 }
 
+/// Smith's auxiliary function of the anisotropic GTR2 distribution, `G1 = 1 / (1 + lambda)`.
+fn gtr2_lambda(v_local: Vec3A, ax: f32, ay: f32) -> f32 {
+    let tan2 = ((v_local.x * ax).powi(2) + (v_local.y * ay).powi(2)) / (v_local.z * v_local.z);
+    (-1. + (1. + tan2).sqrt()) / 2.
+}
+
+/// Samples a half vector among the GTR2 normals visible from `wi`.
+/// Eric Heitz, "Sampling the GGX Distribution of Visible Normals", JCGT 2018
+/// https://jcgt.org/published/0007/04/01/paper.pdf
+fn sample_gtr2_vndf(wi: Vec3A, ax: f32, ay: f32, u: Vec2) -> Vec3A {
+    // stretch the view so the distribution becomes the hemisphere
+    let vh = vec3a(ax * wi.x, ay * wi.y, wi.z).normalize();
+    let len_sq = vh.x * vh.x + vh.y * vh.y;
+    let t1 = if len_sq > 0. { vec3a(-vh.y, vh.x, 0.) / len_sq.sqrt() } else { Vec3A::X };
+    let t2 = vh.cross(t1);
+    // disk sample, squashed onto the part of the hemisphere facing vh
+    let r = u.x.sqrt();
+    let (sin_phi, cos_phi) = (2. * PI * u.y).sin_cos();
+    let p1 = r * cos_phi;
+    let s = 0.5 * (1. + vh.z);
+    let p2 = (1. - s) * (1. - p1 * p1).sqrt() + s * r * sin_phi;
+    let nh = p1 * t1 + p2 * t2 + (1. - p1 * p1 - p2 * p2).max(0.).sqrt() * vh;
+    vec3a(ax * nh.x, ay * nh.y, nh.z.max(1e-6)).normalize()
+}
+
+/// Density of `sample_gtr2_vndf` picking `h`, `G1(wi) * max(0, wi.h) * D(h) / wi.z`.
+fn gtr2_vndf_pdf(wi: Vec3A, h: Vec3A, ax: f32, ay: f32) -> f32 {
+    let g1 = 1. / (1. + gtr2_lambda(wi, ax, ay));
+    g1 * wi.dot(h).max(0.) * gtr2_ansio(h, ax, ay) / wi.z
+}
+
+/// Samples a half vector proportional to `gtr1(n_dot_h, a) * n_dot_h`.
+fn sample_gtr1(a: f32, u: Vec2) -> Vec3A {
+    if a >= 1. {
+        return sample_cosine_hemisphere(u);
+    }
+    let a2 = a * a;
+    let cos_theta = ((1. - a2.powf(1. - u.x)) / (1. - a2)).max(0.).sqrt();
+    let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+    let (sin_phi, cos_phi) = (2. * PI * u.y).sin_cos();
+    vec3a(sin_theta * cos_phi, sin_theta * sin_phi, cos_theta)
+}
+
+/// Turns a half vector density into the density of the reflected direction `wo`.
+fn reflect_pdf(pdf_h: f32, h: Vec3A, wo: Vec3A) -> f32 {
+    let o_dot_h = wo.dot(h).abs();
+    if o_dot_h > 0. { pdf_h / (4. * o_dot_h) } else { 0. }
+}
+
+fn reflect_local(wi: Vec3A, h: Vec3A) -> Vec3A {
+    -wi + 2. * wi.dot(h) * h
+}
+
 // geometric distribution function
 fn smith_geo_ggx(n_dot_v: f32, alpha: f32) -> f32 {
     let a = alpha * alpha;
@@ -128,6 +225,158 @@ pub fn fresnel_dielectric_2(n_dot_i: f32, eta: f32) -> f32 {
     fresnel_dielectric(n_dot_i.abs(), n_dot_t, eta)
 }
 
+/// Fresnel equation of a conductor with complex index of refraction `eta + i k`, per channel.
+/// https://seblagarde.wordpress.com/2013/04/29/memo-on-fresnel-equations/
+/// n_dot_i: cos(incident angle)
+pub fn fresnel_conductor(n_dot_i: f32, eta: Vec3A, k: Vec3A) -> Vec3A {
+    let cos2 = n_dot_i.clamp(0., 1.).powi(2);
+    let sin2 = 1. - cos2;
+    let eta2 = eta * eta;
+    let k2 = k * k;
+
+    let t0 = eta2 - k2 - sin2;
+    let a2_plus_b2 = (t0 * t0 + 4. * eta2 * k2).powf(0.5);
+    let t1 = a2_plus_b2 + cos2;
+    let a = (0.5 * (a2_plus_b2 + t0)).max(Vec3A::ZERO).powf(0.5);
+    let t2 = 2. * n_dot_i * a;
+    let rs = (t1 - t2) / (t1 + t2);
+
+    let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+
+    0.5 * (rp + rs)
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Complex {
+    re: f32,
+    im: f32,
+}
+
+impl Complex {
+    fn new(re: f32, im: f32) -> Self {
+        Self { re, im }
+    }
+
+    fn real(re: f32) -> Self {
+        Self { re, im: 0. }
+    }
+
+    fn norm_sqr(self) -> f32 {
+        self.re * self.re + self.im * self.im
+    }
+
+    /// Principal square root.
+    fn sqrt(self) -> Self {
+        let n = self.norm_sqr().sqrt();
+        let re = (0.5 * (n + self.re)).max(0.).sqrt();
+        let im = (0.5 * (n - self.re)).max(0.).sqrt();
+        Self { re, im: if self.im < 0. { -im } else { im } }
+    }
+}
+
+impl Add for Complex {
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self {
+        Self::new(self.re + rhs.re, self.im + rhs.im)
+    }
+}
+
+impl Sub for Complex {
+    type Output = Self;
+    fn sub(self, rhs: Self) -> Self {
+        Self::new(self.re - rhs.re, self.im - rhs.im)
+    }
+}
+
+impl Mul for Complex {
+    type Output = Self;
+    fn mul(self, rhs: Self) -> Self {
+        Self::new(self.re * rhs.re - self.im * rhs.im, self.re * rhs.im + self.im * rhs.re)
+    }
+}
+
+impl Div for Complex {
+    type Output = Self;
+    fn div(self, rhs: Self) -> Self {
+        let d = rhs.norm_sqr();
+        Self::new((self.re * rhs.re + self.im * rhs.im) / d, (self.im * rhs.re - self.re * rhs.im) / d)
+    }
+}
+
+/// Reflectance at one wavelength of a film of index `n2` and `thickness` nanometers,
+/// between the incident medium `n1` and a base of complex index `n3`, averaged over both polarizations.
+/// Airy summation of the light bouncing inside the film, see Belcour and Barla,
+/// "A Practical Extension to Microfacet Theory for the Modeling of Varying Iridescence", SIGGRAPH 2017
+fn thin_film_reflectance(cos1: f32, n1: f32, n2: f32, n3: Complex, thickness: f32, lambda: f32) -> f32 {
+    let cos1 = cos1.clamp(0., 1.);
+    let sin1_sq = 1. - cos1 * cos1;
+    let sin2_sq = sin1_sq * (n1 / n2).powi(2);
+    if sin2_sq >= 1. {
+        // total internal reflection at the top of the film
+        return 1.;
+    }
+    let cos2 = (1. - sin2_sq).sqrt();
+    let sin3_sq = Complex::real(sin1_sq * n1 * n1) / (n3 * n3);
+    let cos3 = (Complex::real(1.) - sin3_sq).sqrt();
+
+    let r12_s = (n1 * cos1 - n2 * cos2) / (n1 * cos1 + n2 * cos2);
+    let r12_p = (n2 * cos1 - n1 * cos2) / (n2 * cos1 + n1 * cos2);
+    let (n2, cos2_c) = (Complex::real(n2), Complex::real(cos2));
+    let r23_s = (n2 * cos2_c - n3 * cos3) / (n2 * cos2_c + n3 * cos3);
+    let r23_p = (n3 * cos2_c - n2 * cos3) / (n3 * cos2_c + n2 * cos3);
+
+    // phase difference of one round trip through the film
+    let delta = 4. * PI * n2.re * thickness * cos2 / lambda;
+    let phase = Complex::new(delta.cos(), delta.sin());
+    let airy = |r12: f32, r23: Complex| {
+        let t = r23 * phase;
+        (Complex::real(r12) + t).norm_sqr() / (Complex::real(1.) + Complex::real(r12) * t).norm_sqr()
+    };
+    0.5 * (airy(r12_s, r23_s) + airy(r12_p, r23_p))
+}
+
+/// A thin transparent coating on an interface, soap bubbles, oil slicks or lens coatings.
+/// Replaces the Fresnel term of `Dielectric`, `Conductor`, `RoughDielectric`, `RoughPlastic`,
+/// `DisneyMetal` and `DisneyPrincipled` when set.
+pub struct ThinFilm {
+    pub ior: f32,
+    /// Film thickness in nanometers.
+    pub thickness: f32,
+    /// Scales `thickness` by its red channel, e.g. a `PerlinTex` for swirls.
+    pub thickness_map: Option<Arc<dyn Texture>>,
+}
+
+impl ThinFilm {
+    // wavelengths in nanometers the red, green and blue channels are evaluated at
+    const LAMBDA: [f32; 3] = [630., 532., 465.];
+
+    /// Reflectance per channel from the medium of index `n1` into a base `eta + i k`.
+    pub fn reflectance(&self, rec: &HitRecord, cos_i: f32, n1: f32, eta: Vec3A, k: Vec3A) -> Vec3A {
+        let thickness = match &self.thickness_map {
+            Some(map) => self.thickness * map.value(rec.uv, rec.p).x,
+            None => self.thickness,
+        };
+        let r = |i: usize| thin_film_reflectance(cos_i, n1, self.ior, Complex::new(eta[i], k[i]), thickness, Self::LAMBDA[i]);
+        vec3a(r(0), r(1), r(2))
+    }
+
+    /// Reflectance from the medium of index `n1` into a dielectric base of index `n3`.
+    pub fn over_dielectric(&self, rec: &HitRecord, cos_i: f32, n1: f32, n3: f32) -> Vec3A {
+        self.reflectance(rec, cos_i, n1, Vec3A::splat(n3), Vec3A::ZERO)
+    }
+
+    /// Reflectance from the outside onto a base only known by its reflectance `f0` at normal incidence,
+    /// as used by the Schlick Fresnel of the Disney lobes. The base is taken as the conductor of index
+    /// `1 + i k` with that `f0`, `k = 2 sqrt(f0 / (1 - f0))`.
+    pub fn over_f0(&self, rec: &HitRecord, cos_i: f32, f0: Vec3A) -> Vec3A {
+        let f0 = f0.clamp(Vec3A::ZERO, Vec3A::splat(0.999));
+        let k = 2. * (f0 / (1. - f0)).powf(0.5);
+        self.reflectance(rec, cos_i, 1., Vec3A::ONE, k)
+    }
+}
+
 /// The masking term models the occlusion between the small mirrors of the microfacet models.
 /// See Eric Heitz's paper "Understanding the Masking-Shadowing Function in Microfacet-Based BRDFs"
 /// for a great explanation.
@@ -155,39 +404,139 @@ pub struct RoughPlastic {
     pub diff_color: Arc<dyn Texture>,
     pub roughness: f32,
     pub eta: f32,
+    pub film: Option<ThinFilm>,
 }
 
 impl Material for RoughPlastic {
-    fn scatter(&self, r_in: &Ray, rec: &HitRecord, attenuation: &mut Vec3A, scattered: &mut Ray) -> bool {
-        let p = offset_hit_point(rec.p, rec.norm);
-        let dir_o = random_on_hemisphere(rec.norm);
-        let n_dot_i = rec.norm.dot(-r_in.d);
-        let n_dot_o = rec.norm.dot(dir_o);
-        let h = (dir_o - r_in.d).normalize();
-        let h_dot_i = h.dot(-r_in.d);
-        let h_dot_o = h.dot(dir_o);
-        let n_dot_h = rec.norm.dot(h);
+    fn flags(&self) -> LobeFlags {
+        LobeFlags::DIFFUSE | LobeFlags::GLOSSY | LobeFlags::REFLECTION
+    }
+
+    fn sample(&self, rec: &HitRecord, wi: Vec3A, uc: f32, u: Vec2) -> Option<BsdfSample> {
+        if wi.z <= 0. {
+            return None;
+        }
+        let wo = if uc < self.spec_prob(rec, wi) {
+            let alpha = self.roughness.clamp(0.01, 1.);
+            reflect_local(wi, sample_gtr2_vndf(wi, alpha, alpha, u))
+        } else {
+            sample_cosine_hemisphere(u)
+        };
+        let pdf = self.pdf(rec, wi, wo);
+        if pdf == 0. {
+            return None;
+        }
+        Some(BsdfSample { wo, weight: self.eval(rec, wi, wo) / pdf, pdf, flags: self.flags(), exit: None })
+    }
+
+    fn eval(&self, rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> Vec3A {
+        let n_dot_i = wi.z;
+        let n_dot_o = wo.z;
+        if n_dot_i <= 0. || n_dot_o <= 0. {
+            return Vec3A::ZERO;
+        }
+        let h = (wi + wo).normalize();
+        let h_dot_i = h.dot(wi);
+        let h_dot_o = h.dot(wo);
+        let n_dot_h = h.z;
 
 
         let kd = self.diff_color.value(rec.uv, rec.p);
         let ks = self.spec_color.value(rec.uv, rec.p);
 
         let roughness = self.roughness.clamp(0.01, 1.);
-        let f_o = fresnel_dielectric_2(h_dot_o, self.eta);
+        let f_o = self.fresnel(rec, h_dot_o);
         let d = gtr2(n_dot_h, roughness);
-        let g = smith_masking_gtr2_2(-r_in.d, rec.norm, roughness)
-                    *smith_masking_gtr2_2(dir_o, rec.norm, roughness);
+        let g = smith_masking_gtr2(wi, roughness)
+                    *smith_masking_gtr2(wo, roughness);
         let spec_contrib = ks * (g * f_o * d) / (4. * n_dot_i * n_dot_o);
 
-        let f_i = fresnel_dielectric_2(h_dot_i, self.eta);
+        let f_i = self.fresnel(rec, h_dot_i);
         let diff_contrib = kd * (1. - f_o) * (1. - f_i) * FRAC_1_PI;
 
-        *scattered = Ray {o: p, d: dir_o, s: r_in.s};
-        *attenuation = (spec_contrib + diff_contrib) * n_dot_o * 2. * PI;
-        true
+        (spec_contrib + diff_contrib) * n_dot_o
+    }
+
+    fn pdf(&self, rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> f32 {
+        if wi.z <= 0. || wo.z <= 0. {
+            return 0.;
+        }
+        let alpha = self.roughness.clamp(0.01, 1.);
+        let h = (wi + wo).normalize();
+        let spec_pdf = reflect_pdf(gtr2_vndf_pdf(wi, h, alpha, alpha), h, wo);
+        let spec_prob = self.spec_prob(rec, wi);
+        spec_prob * spec_pdf + (1. - spec_prob) * cosine_hemisphere_pdf(wo.z)
     }
 }
 
+impl RoughPlastic {
+    fn fresnel(&self, rec: &HitRecord, cos_i: f32) -> Vec3A {
+        match &self.film {
+            Some(film) => film.over_dielectric(rec, cos_i, 1., self.eta),
+            None => Vec3A::splat(fresnel_dielectric_2(cos_i, self.eta)),
+        }
+    }
+
+    /// Chance of sampling the specular lobe, by how much each lobe reflects at normal incidence of `wi`.
+    fn spec_prob(&self, rec: &HitRecord, wi: Vec3A) -> f32 {
+        let f = luminance(self.fresnel(rec, wi.z));
+        let spec = f * luminance(self.spec_color.value(rec.uv, rec.p));
+        let diff = (1. - f) * luminance(self.diff_color.value(rec.uv, rec.p));
+        if spec + diff > 0. { (spec / (spec + diff)).clamp(0.1, 0.9) } else { 0.5 }
+    }
+}
+
+
+fn luminance(c: Vec3A) -> f32 {
+    vec3a(0.3, 0.6, 0.1).dot(c)
+}
+
+/// Burley's diffuse blended with its Hanrahan-Krueger like subsurface term, without the albedo.
+fn disney_diffuse(wi: Vec3A, wo: Vec3A, roughness: f32, subsurface: f32) -> f32 {
+    let h = (wi + wo).normalize();
+    let h_dot_o = h.dot(wo);
+
+    let fo = schlick_fresnel(wo.z);
+    let fi = schlick_fresnel(wi.z);
+
+    let fd90 = 0.5 + 2. * h_dot_o * h_dot_o * roughness;
+    let fd = lerp(1.0, fd90, fo) * lerp(1.0, fd90, fi);
+
+    let fss90 = roughness * h_dot_o * h_dot_o;
+    let fss_wi = lerp(1., fss90, fi);
+    let fss_wo = lerp(1., fss90, fo);
+    let fss = 1.25 * (fss_wi * fss_wo * (1. / (wi.z + wo.z) - 0.5) + 0.5);
+
+    lerp(fd, fss, subsurface) * FRAC_1_PI
+}
+
+fn disney_sheen(albedo: Vec3A, tint: f32, h_dot_o: f32) -> Vec3A {
+    let luminance = luminance(albedo);
+    let c_tint = if luminance > 0. { albedo / luminance } else { Vec3A::ONE };
+    let c_sheen = Vec3A::ONE.lerp(c_tint, tint);
+    c_sheen * schlick_fresnel(h_dot_o)
+}
+
+fn disney_clearcoat(wi: Vec3A, wo: Vec3A, alpha: f32) -> f32 {
+    let h = (wi + wo).normalize();
+    let fc = lerp(0.4, 1., schlick_fresnel(h.dot(wo)));
+    let dc = gtr1(h.z, alpha);
+    let gc = smith_geo_ggx(wi.z, 0.25) * smith_geo_ggx(wo.z, 0.25);
+    0.25 * fc * dc * gc
+}
+
+fn clearcoat_alpha(clearcoat_gloss: f32) -> f32 {
+    lerp(0.1, 0.001, clearcoat_gloss)
+}
+
+/// GTR2 roughness along the tangent and the bitangent.
+fn disney_alpha(roughness: f32, anisotropic: f32) -> (f32, f32) {
+    let alpha_min = 0.0001;
+    let aspect = (1. - 0.9 * anisotropic).sqrt();
+    let ax = (roughness * roughness / aspect).max(alpha_min);
+    let ay = (roughness * roughness * aspect).max(alpha_min);
+    (ax, ay)
+}
 
 pub struct DisneyDiffuse {
     pub albedo: Arc<dyn Texture>,
@@ -196,29 +545,24 @@ pub struct DisneyDiffuse {
 }
 
 impl Material for DisneyDiffuse {
-    fn scatter(&self, r_in: &Ray, rec: &HitRecord, attenuation: &mut Vec3A, scattered: &mut Ray) -> bool {
-        let p = offset_hit_point(rec.p, rec.norm);
-        let dir_o = random_on_hemisphere(rec.norm);
-        let n_dot_i = rec.norm.dot(-r_in.d);
-        let n_dot_o = rec.norm.dot(dir_o);
-        let h = (dir_o - r_in.d).normalize();
-        let h_dot_o = h.dot(dir_o);
-
-        let fo = schlick_fresnel(n_dot_o);
-        let fi = schlick_fresnel(n_dot_i);
-
-        let fd90 = 0.5 + 2. * h_dot_o * h_dot_o * self.roughness;
-        let fd = lerp(1.0, fd90, fo) * lerp(1.0, fd90, fi);
+    fn flags(&self) -> LobeFlags {
+        LobeFlags::DIFFUSE | LobeFlags::REFLECTION
+    }
 
-        let fss90 = self.roughness * h_dot_o * h_dot_o;
-        let fss_wi = lerp(1., fss90, fi);
-        let fss_wo = lerp(1., fss90, fo);
-        let fss = 1.25 * (fss_wi * fss_wo * (1. / (n_dot_i + n_dot_o) - 0.5) + 0.5);
+    fn sample(&self, rec: &HitRecord, wi: Vec3A, _uc: f32, u: Vec2) -> Option<BsdfSample> {
+        sample_hemisphere(self, rec, wi, u)
+    }
 
+    fn eval(&self, rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> Vec3A {
+        let n_dot_o = wo.z;
+        if n_dot_o <= 0. {
+            return Vec3A::ZERO;
+        }
+        self.albedo.value(rec.uv, rec.p) * disney_diffuse(wi, wo, self.roughness, self.subsurface) * n_dot_o
+    }
 
-        *scattered = Ray {o: p, d: dir_o, s: r_in.s};
-        *attenuation = self.albedo.value(rec.uv, rec.p) * lerp(fd, fss, self.subsurface) * 2. * n_dot_o;
-        true
+    fn pdf(&self, _rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> f32 {
+        hemisphere_pdf(wi, wo)
     }
 }
 pub struct DisneyMetal {
@@ -226,84 +570,121 @@ pub struct DisneyMetal {
     pub roughness: f32,
     pub anisotropic: f32,
     pub rot: f32,
+    pub film: Option<ThinFilm>,
 }
 
 
 impl Material for DisneyMetal {
-    fn scatter(&self, r_in: &Ray, rec: &HitRecord, attenuation: &mut Vec3A, scattered: &mut Ray) -> bool {
-        let p = offset_hit_point(rec.p, rec.norm);
-        let dir_o = random_on_hemisphere(rec.norm);
-        let n_dot_i = rec.norm.dot(-r_in.d);
-        let n_dot_o = rec.norm.dot(dir_o);
-        let h = (dir_o - r_in.d).normalize();
-        let h_dot_o = h.dot(dir_o);
-        let n_dot_h = rec.norm.dot(h);
+    fn flags(&self) -> LobeFlags {
+        LobeFlags::GLOSSY | LobeFlags::REFLECTION
+    }
 
-        let albedo = self.albedo.value(rec.uv, rec.p);
+    fn sample(&self, rec: &HitRecord, wi: Vec3A, _uc: f32, u: Vec2) -> Option<BsdfSample> {
+        if wi.z <= 0. {
+            return None;
+        }
+        let (ax, ay, rot) = self.alpha();
+        let h = rotate_tangent(sample_gtr2_vndf(rotate_tangent(wi, rot), ax, ay, u), -rot);
+        let wo = reflect_local(wi, h);
+        let pdf = self.pdf(rec, wi, wo);
+        if pdf == 0. {
+            return None;
+        }
+        Some(BsdfSample { wo, weight: self.eval(rec, wi, wo) / pdf, pdf, flags: self.flags(), exit: None })
+    }
 
-        let fm = albedo.lerp(Vec3A::ONE, schlick_fresnel(h_dot_o));
-        let alpha_min = 0.0001;
+    fn eval(&self, rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> Vec3A {
+        let n_dot_i = wi.z;
+        let n_dot_o = wo.z;
+        if n_dot_o <= 0. {
+            return Vec3A::ZERO;
+        }
+        let h = (wi + wo).normalize();
+        let h_dot_o = h.dot(wo);
+        let n_dot_h = h.z;
 
-        let (dm, gm) = if self.anisotropic > -10. {
-            let aspect = (1. - 0.9 * self.anisotropic).sqrt();
-            let ax = (self.roughness * self.roughness / aspect).max(alpha_min);
-            let ay = (self.roughness * self.roughness * aspect).max(alpha_min);
+        let albedo = self.albedo.value(rec.uv, rec.p);
 
-            let rot = self.rot * 2. * PI;
+        let fm = match &self.film {
+            Some(film) => film.over_f0(rec, h_dot_o, albedo),
+            None => albedo.lerp(Vec3A::ONE, schlick_fresnel(h_dot_o)),
+        };
+        let (ax, ay, rot) = self.alpha();
 
-            let h_local = rec.world_to_local_with_rot(h, rot);
+        let (dm, gm) = if self.anisotropic > -10. {
+            let h_local = rotate_tangent(h, rot);
             let dm = gtr2_ansio(h_local, ax, ay);
 
-            let i_local = rec.world_to_local_with_rot(-r_in.d, rot);
-            let o_local = rec.world_to_local_with_rot(dir_o, rot);
+            let i_local = rotate_tangent(wi, rot);
+            let o_local = rotate_tangent(wo, rot);
 
             let gm = smith_geo_ggx_aniso(i_local, ax, ay) * smith_geo_ggx_aniso(o_local, ax, ay);
 
             (dm, gm)
         } else {
-            let r2 = (self.roughness * self.roughness).max(alpha_min);
-            let dm = gtr2(n_dot_h, r2);
+            let dm = gtr2(n_dot_h, ax);
 
-            let gm = smith_geo_ggx(n_dot_i, r2) * smith_geo_ggx(n_dot_o, r2);
+            let gm = smith_geo_ggx(n_dot_i, ax) * smith_geo_ggx(n_dot_o, ax);
 
             (dm, gm)
         };
 
         let metal_w = fm * dm * gm;
 
-        *scattered = Ray {o: p, d: dir_o, s: r_in.s};
-        *attenuation = metal_w * n_dot_o * 2. * PI;
-        true
+        metal_w * n_dot_o
+    }
+
+    fn pdf(&self, _rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> f32 {
+        if wi.z <= 0. || wo.z <= 0. {
+            return 0.;
+        }
+        let (ax, ay, rot) = self.alpha();
+        let h = (wi + wo).normalize();
+        let pdf_h = gtr2_vndf_pdf(rotate_tangent(wi, rot), rotate_tangent(h, rot), ax, ay);
+        reflect_pdf(pdf_h, h, wo)
+    }
+}
+
+impl DisneyMetal {
+    /// GTR2 roughness along the rotated tangent and bitangent, and the rotation, as used by `eval`.
+    fn alpha(&self) -> (f32, f32, f32) {
+        if self.anisotropic > -10. {
+            let (ax, ay) = disney_alpha(self.roughness, self.anisotropic);
+            (ax, ay, self.rot * 2. * PI)
+        } else {
+            let (a, _) = disney_alpha(self.roughness, 0.);
+            (a, a, 0.)
+        }
     }
 }
 
 
+
 pub struct DisneySheen {
     pub albedo: Arc<dyn Texture>,
     pub tint: f32,
 }
 
 impl Material for DisneySheen {
-    fn scatter(&self, r_in: &Ray, rec: &HitRecord, attenuation: &mut Vec3A, scattered: &mut Ray) -> bool {
-        let p = offset_hit_point(rec.p, rec.norm);
-        let dir_o = random_on_hemisphere(rec.norm);
-        let n_dot_i = rec.norm.dot(-r_in.d);
-        let n_dot_o = rec.norm.dot(dir_o);
-        let h = (dir_o - r_in.d).normalize();
-        let h_dot_i = h.dot(-r_in.d);
-        let h_dot_o = h.dot(dir_o);
-        let n_dot_h = rec.norm.dot(h);
+    fn flags(&self) -> LobeFlags {
+        LobeFlags::GLOSSY | LobeFlags::REFLECTION
+    }
 
-        let albedo = self.albedo.value(rec.uv, rec.p);
+    fn sample(&self, rec: &HitRecord, wi: Vec3A, _uc: f32, u: Vec2) -> Option<BsdfSample> {
+        sample_hemisphere(self, rec, wi, u)
+    }
 
-        let luminance = vec3a(0.3, 0.6, 0.1).dot(albedo);
-        let c_tint = if luminance > 0. { albedo / luminance } else { Vec3A::ONE };
-        let c_sheen = Vec3A::ONE.lerp(c_tint, self.tint);
-        let f_sheen = c_sheen * schlick_fresnel(h_dot_o);
+    fn eval(&self, rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> Vec3A {
+        let n_dot_o = wo.z;
+        if n_dot_o <= 0. {
+            return Vec3A::ZERO;
+        }
+        let h = (wi + wo).normalize();
+        disney_sheen(self.albedo.value(rec.uv, rec.p), self.tint, h.dot(wo)) * n_dot_o
+    }
 
-        *scattered = Ray {o: p, d: dir_o, s: r_in.s};
-        *attenuation = f_sheen * n_dot_o * 2. * PI;
-        true
+    fn pdf(&self, _rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> f32 {
+        hemisphere_pdf(wi, wo)
     }
 }
 
@@ -312,24 +693,797 @@ pub struct DisneyClearcoat {
 }
 
 impl Material for DisneyClearcoat {
-    fn scatter(&self, r_in: &Ray, rec: &HitRecord, attenuation: &mut Vec3A, scattered: &mut Ray) -> bool {
-        let p = offset_hit_point(rec.p, rec.norm);
-        let dir_o = random_on_hemisphere(rec.norm);
-        let n_dot_i = rec.norm.dot(-r_in.d);
-        let n_dot_o = rec.norm.dot(dir_o);
-        let h = (dir_o - r_in.d).normalize();
-        let h_dot_i = h.dot(-r_in.d);
-        let h_dot_o = h.dot(dir_o);
-        let n_dot_h = rec.norm.dot(h);
-
-        let fc = lerp(0.4, 1., schlick_fresnel(h_dot_o));
-        let dc = gtr1(n_dot_h, lerp(0.1, 0.001, self.clearcoat_gloss));
-        let gc = smith_geo_ggx(n_dot_i, 0.25) * smith_geo_ggx(n_dot_o, 0.25);
-
-        let cc = 0.25 * fc * dc * gc;
-
-        *scattered = Ray {o: p, d: dir_o, s: r_in.s};
-        *attenuation = Vec3A::splat(cc) * n_dot_o * 2. * PI;
-        true
+    fn flags(&self) -> LobeFlags {
+        LobeFlags::GLOSSY | LobeFlags::REFLECTION
+    }
+
+    fn sample(&self, rec: &HitRecord, wi: Vec3A, _uc: f32, u: Vec2) -> Option<BsdfSample> {
+        if wi.z <= 0. {
+            return None;
+        }
+        let wo = reflect_local(wi, sample_gtr1(self.alpha(), u));
+        let pdf = self.pdf(rec, wi, wo);
+        if pdf == 0. {
+            return None;
+        }
+        Some(BsdfSample { wo, weight: self.eval(rec, wi, wo) / pdf, pdf, flags: self.flags(), exit: None })
+    }
+
+    fn eval(&self, _rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> Vec3A {
+        let n_dot_o = wo.z;
+        if n_dot_o <= 0. {
+            return Vec3A::ZERO;
+        }
+        Vec3A::splat(disney_clearcoat(wi, wo, self.alpha())) * n_dot_o
+    }
+
+    fn pdf(&self, _rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> f32 {
+        if wi.z <= 0. || wo.z <= 0. {
+            return 0.;
+        }
+        let h = (wi + wo).normalize();
+        reflect_pdf(gtr1(h.z, self.alpha()) * h.z, h, wo)
+    }
+}
+
+impl DisneyClearcoat {
+    fn alpha(&self) -> f32 {
+        clearcoat_alpha(self.clearcoat_gloss)
+    }
+}
+
+/// Generalized half vector of a reflection or refraction between `wi` and `wo`, facing +z.
+/// `None` for the degenerate configurations and when a side sees the back of the microfacet.
+fn dielectric_half_vector(wi: Vec3A, wo: Vec3A, eta: f32) -> Option<Vec3A> {
+    let etap = if same_hemisphere(wi, wo) { 1. } else if wi.z > 0. { eta } else { 1. / eta };
+    let h = wi + wo * etap;
+    if wi.z == 0. || wo.z == 0. || h.length_squared() == 0. {
+        return None;
+    }
+    let h = h.normalize();
+    let h = if h.z < 0. { -h } else { h };
+    if h.dot(wi) * wi.z < 0. || h.dot(wo) * wo.z < 0. {
+        return None;
+    }
+    Some(h)
+}
+
+/// GGX reflection and refraction of a rough dielectric interface, times |cos(wo)|.
+/// Bruce Walter et al., "Microfacet Models for Refraction through Rough Surfaces", EGSR 2007
+/// eta: eta_transmission / eta_incident, for `wi` above the surface.
+/// Transmission is scaled by 1 / eta^2 since we carry radiance.
+fn rough_dielectric_eval(wi: Vec3A, wo: Vec3A, eta: f32, roughness: f32) -> f32 {
+    rough_dielectric_eval_with(wi, wo, eta, roughness, |cos| Vec3A::splat(fresnel_dielectric_2(cos, eta))).x
+}
+
+/// `rough_dielectric_eval` with the Fresnel term of a microfacet given by `fresnel(wi.h)`, like that of a `ThinFilm`.
+fn rough_dielectric_eval_with(wi: Vec3A, wo: Vec3A, eta: f32, roughness: f32, fresnel: impl Fn(f32) -> Vec3A) -> Vec3A {
+    let h = match dielectric_half_vector(wi, wo, eta) {
+        Some(h) => h,
+        None => return Vec3A::ZERO,
+    };
+    let alpha = roughness * roughness;
+    let f = fresnel(wi.dot(h));
+    let d = gtr2(h.z, alpha);
+    let g = smith_masking_gtr2(wi, roughness) * smith_masking_gtr2(wo, roughness);
+    if same_hemisphere(wi, wo) {
+        f * d * g / (4. * wi.z.abs())
+    } else {
+        let denom = (wo.dot(h) + wi.dot(h) / eta).powi(2);
+        (1. - f) * d * g * (wi.dot(h) * wo.dot(h)).abs() / (wi.z.abs() * denom * eta * eta)
+    }
+}
+
+/// Chance of reflecting off a microfacet with Fresnel term `f`, when only `lobes` may be sampled.
+/// A tinted `f` is picked by the average of its channels, like `Dielectric` does.
+fn reflect_prob(f: Vec3A, lobes: LobeFlags) -> Option<f32> {
+    let f = (f.x + f.y + f.z) / 3.;
+    let pr = if lobes.contains(LobeFlags::REFLECTION) { f } else { 0. };
+    let pt = if lobes.contains(LobeFlags::TRANSMISSION) { 1. - f } else { 0. };
+    if pr + pt > 0. { Some(pr / (pr + pt)) } else { None }
+}
+
+fn rough_dielectric_pdf(wi: Vec3A, wo: Vec3A, eta: f32, roughness: f32, lobes: LobeFlags) -> f32 {
+    rough_dielectric_pdf_with(wi, wo, eta, roughness, lobes, |cos| Vec3A::splat(fresnel_dielectric_2(cos, eta)))
+}
+
+fn rough_dielectric_pdf_with(
+    wi: Vec3A,
+    wo: Vec3A,
+    eta: f32,
+    roughness: f32,
+    lobes: LobeFlags,
+    fresnel: impl Fn(f32) -> Vec3A,
+) -> f32 {
+    let h = match dielectric_half_vector(wi, wo, eta) {
+        Some(h) => h,
+        None => return 0.,
+    };
+    let alpha = roughness * roughness;
+    let pr = match reflect_prob(fresnel(wi.dot(h)), lobes) {
+        Some(pr) => pr,
+        None => return 0.,
+    };
+    let pdf_h = gtr2_vndf_pdf(wi, h, alpha, alpha);
+    if same_hemisphere(wi, wo) {
+        pr * reflect_pdf(pdf_h, h, wo)
+    } else {
+        let denom = (wo.dot(h) + wi.dot(h) / eta).powi(2);
+        (1. - pr) * pdf_h * wo.dot(h).abs() / denom
+    }
+}
+
+/// Picks a visible microfacet, then reflects with probability of its Fresnel term or refracts.
+fn rough_dielectric_sample(wi: Vec3A, eta: f32, roughness: f32, uc: f32, u: Vec2, lobes: LobeFlags) -> Option<Vec3A> {
+    rough_dielectric_sample_with(wi, eta, roughness, uc, u, lobes, |cos| Vec3A::splat(fresnel_dielectric_2(cos, eta)))
+}
+
+fn rough_dielectric_sample_with(
+    wi: Vec3A,
+    eta: f32,
+    roughness: f32,
+    uc: f32,
+    u: Vec2,
+    lobes: LobeFlags,
+    fresnel: impl Fn(f32) -> Vec3A,
+) -> Option<Vec3A> {
+    let alpha = roughness * roughness;
+    let h = sample_gtr2_vndf(wi, alpha, alpha, u);
+    let wo = if uc < reflect_prob(fresnel(wi.dot(h)), lobes)? {
+        reflect_local(wi, h)
+    } else {
+        refract(-wi, h, 1. / eta).normalize()
+    };
+    Some(wo).filter(|wo| wo.z != 0.)
+}
+
+/// Frosted glass, `Dielectric` with a GGX rough interface.
+/// `absorption` is the Beer-Lambert coefficient per unit length inside, applied on the way out
+/// over the distance from the previous vertex, so it assumes nothing else sits inside the object.
+pub struct RoughDielectric {
+    pub ior: f32,
+    pub roughness: f32,
+    pub absorption: Vec3A,
+    pub film: Option<ThinFilm>,
+}
+
+impl RoughDielectric {
+    fn eta(&self, rec: &HitRecord) -> f32 {
+        if rec.front_face { self.ior } else { 1. / self.ior }
+    }
+
+    fn roughness(&self) -> f32 {
+        self.roughness.clamp(0.01, 1.)
+    }
+
+    fn transmittance(&self, rec: &HitRecord) -> Vec3A {
+        if rec.front_face { Vec3A::ONE } else { (-self.absorption * rec.t).exp() }
+    }
+
+    fn fresnel(&self, rec: &HitRecord, cos_i: f32) -> Vec3A {
+        film_fresnel(&self.film, rec, cos_i, self.ior)
+    }
+}
+
+/// Fresnel term of a microfacet on a dielectric interface of index `ior`, through `film` if there is one.
+fn film_fresnel(film: &Option<ThinFilm>, rec: &HitRecord, cos_i: f32, ior: f32) -> Vec3A {
+    let (n1, n3) = if rec.front_face { (1., ior) } else { (ior, 1.) };
+    match film {
+        Some(film) => film.over_dielectric(rec, cos_i, n1, n3),
+        None => Vec3A::splat(fresnel_dielectric_2(cos_i, n3 / n1)),
+    }
+}
+
+impl Material for RoughDielectric {
+    fn flags(&self) -> LobeFlags {
+        LobeFlags::GLOSSY | LobeFlags::REFLECTION | LobeFlags::TRANSMISSION
+    }
+
+    fn sample(&self, rec: &HitRecord, wi: Vec3A, uc: f32, u: Vec2) -> Option<BsdfSample> {
+        if wi.z <= 0. {
+            return None;
+        }
+        let wo = rough_dielectric_sample_with(wi, self.eta(rec), self.roughness(), uc, u, self.flags(), |cos| {
+            self.fresnel(rec, cos)
+        })?;
+        let pdf = self.pdf(rec, wi, wo);
+        if pdf == 0. {
+            return None;
+        }
+        let flags = if wo.z < 0. {
+            LobeFlags::GLOSSY | LobeFlags::TRANSMISSION
+        } else {
+            LobeFlags::GLOSSY | LobeFlags::REFLECTION
+        };
+        Some(BsdfSample { wo, weight: self.eval(rec, wi, wo) / pdf, pdf, flags, exit: None })
+    }
+
+    fn eval(&self, rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> Vec3A {
+        if wi.z <= 0. {
+            return Vec3A::ZERO;
+        }
+        let f = rough_dielectric_eval_with(wi, wo, self.eta(rec), self.roughness(), |cos| self.fresnel(rec, cos));
+        self.transmittance(rec) * f
+    }
+
+    fn pdf(&self, rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> f32 {
+        if wi.z <= 0. {
+            return 0.;
+        }
+        rough_dielectric_pdf_with(wi, wo, self.eta(rec), self.roughness(), self.flags(), |cos| self.fresnel(rec, cos))
+    }
+}
+
+/// A metal described by its complex index of refraction `eta + i k` at red, green and blue,
+/// with an exact Fresnel term and GGX roughness. Roughness 0 is a perfect mirror.
+pub struct Conductor {
+    pub eta: Vec3A,
+    pub k: Vec3A,
+    pub roughness: f32,
+    pub film: Option<ThinFilm>,
+}
+
+// RGB fits of measured spectra, as shipped with Mitsuba
+impl Conductor {
+    pub fn gold(roughness: f32) -> Self {
+        Self { eta: vec3a(0.143119, 0.374957, 1.44248), k: vec3a(3.98316, 2.38572, 1.60322), roughness, film: None }
+    }
+
+    pub fn copper(roughness: f32) -> Self {
+        Self { eta: vec3a(0.200438, 0.924033, 1.10221), k: vec3a(3.91295, 2.45285, 2.14219), roughness, film: None }
+    }
+
+    pub fn aluminium(roughness: f32) -> Self {
+        Self { eta: vec3a(1.65746, 0.880369, 0.521229), k: vec3a(9.22387, 6.26952, 4.837), roughness, film: None }
+    }
+
+    pub fn silver(roughness: f32) -> Self {
+        Self { eta: vec3a(0.155265, 0.116723, 0.138342), k: vec3a(4.82835, 3.12225, 2.14696), roughness, film: None }
+    }
+
+    pub fn chrome(roughness: f32) -> Self {
+        Self { eta: vec3a(4.36968, 2.9167, 1.6547), k: vec3a(5.20643, 4.23136, 3.75495), roughness, film: None }
+    }
+
+    fn is_smooth(&self) -> bool {
+        self.roughness < 1e-3
+    }
+
+    fn fresnel(&self, rec: &HitRecord, cos_i: f32) -> Vec3A {
+        match &self.film {
+            Some(film) => film.reflectance(rec, cos_i, 1., self.eta, self.k),
+            None => fresnel_conductor(cos_i, self.eta, self.k),
+        }
+    }
+}
+
+impl Material for Conductor {
+    fn flags(&self) -> LobeFlags {
+        if self.is_smooth() {
+            LobeFlags::SPECULAR | LobeFlags::REFLECTION
+        } else {
+            LobeFlags::GLOSSY | LobeFlags::REFLECTION
+        }
+    }
+
+    fn sample(&self, rec: &HitRecord, wi: Vec3A, _uc: f32, u: Vec2) -> Option<BsdfSample> {
+        if wi.z <= 0. {
+            return None;
+        }
+        if self.is_smooth() {
+            let weight = self.fresnel(rec, wi.z);
+            return Some(BsdfSample { wo: vec3a(-wi.x, -wi.y, wi.z), weight, pdf: 1., flags: self.flags(), exit: None });
+        }
+        let alpha = self.roughness * self.roughness;
+        let wo = reflect_local(wi, sample_gtr2_vndf(wi, alpha, alpha, u));
+        let pdf = self.pdf(rec, wi, wo);
+        if pdf == 0. {
+            return None;
+        }
+        Some(BsdfSample { wo, weight: self.eval(rec, wi, wo) / pdf, pdf, flags: self.flags(), exit: None })
+    }
+
+    fn eval(&self, rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> Vec3A {
+        if self.is_smooth() || wi.z <= 0. || wo.z <= 0. {
+            return Vec3A::ZERO;
+        }
+        let h = (wi + wo).normalize();
+        let f = self.fresnel(rec, wi.dot(h));
+        let d = gtr2(h.z, self.roughness * self.roughness);
+        let g = smith_masking_gtr2(wi, self.roughness) * smith_masking_gtr2(wo, self.roughness);
+        f * d * g / (4. * wi.z)
+    }
+
+    fn pdf(&self, _rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> f32 {
+        if self.is_smooth() || wi.z <= 0. || wo.z <= 0. {
+            return 0.;
+        }
+        let alpha = self.roughness * self.roughness;
+        let h = (wi + wo).normalize();
+        reflect_pdf(gtr2_vndf_pdf(wi, h, alpha, alpha), h, wo)
+    }
+}
+
+/// Burley's principled BSDF, mixing the Disney lobes above with a rough glass lobe for `spec_trans`.
+/// "Physically Based Shading at Disney", SIGGRAPH 2012
+/// "Extending the Disney BRDF to a BSDF with Integrated Subsurface Scattering", SIGGRAPH 2015
+/// All parameters but `base_color` are read from the red channel of their texture.
+pub struct DisneyPrincipled {
+    pub base_color: Arc<dyn Texture>,
+    pub metallic: Arc<dyn Texture>,
+    pub subsurface: Arc<dyn Texture>,
+    pub specular: Arc<dyn Texture>,
+    pub specular_tint: Arc<dyn Texture>,
+    pub roughness: Arc<dyn Texture>,
+    pub anisotropic: Arc<dyn Texture>,
+    pub sheen: Arc<dyn Texture>,
+    pub sheen_tint: Arc<dyn Texture>,
+    pub clearcoat: Arc<dyn Texture>,
+    pub clearcoat_gloss: Arc<dyn Texture>,
+    pub spec_trans: Arc<dyn Texture>,
+    pub ior: Arc<dyn Texture>,
+    /// Over both the specular and the glass lobe, not the clearcoat.
+    pub film: Option<ThinFilm>,
+}
+
+impl DisneyPrincipled {
+    /// A plain dielectric with the defaults of the paper, override fields with `..DisneyPrincipled::new(col)`.
+    pub fn new(base_color: Arc<dyn Texture>) -> Self {
+        let constant = |v: f32| -> Arc<dyn Texture> { Arc::new(ConstantTex { col: Vec3A::splat(v) }) };
+        Self {
+            base_color,
+            metallic: constant(0.),
+            subsurface: constant(0.),
+            specular: constant(0.5),
+            specular_tint: constant(0.),
+            roughness: constant(0.5),
+            anisotropic: constant(0.),
+            sheen: constant(0.),
+            sheen_tint: constant(0.5),
+            clearcoat: constant(0.),
+            clearcoat_gloss: constant(1.),
+            spec_trans: constant(0.),
+            ior: constant(1.5),
+            film: None,
+        }
+    }
+
+    /// Fresnel term of the glass lobe, where `p.eta` is already flipped for hits from inside.
+    fn glass_fresnel(&self, rec: &HitRecord, p: &PrincipledParams, cos_i: f32) -> Vec3A {
+        let ior = if rec.front_face { p.eta } else { 1. / p.eta };
+        film_fresnel(&self.film, rec, cos_i, ior)
+    }
+
+    fn params(&self, rec: &HitRecord) -> PrincipledParams {
+        let scalar = |t: &Arc<dyn Texture>| t.value(rec.uv, rec.p).x;
+        let ior = scalar(&self.ior);
+        PrincipledParams {
+            base_color: self.base_color.value(rec.uv, rec.p),
+            metallic: scalar(&self.metallic),
+            subsurface: scalar(&self.subsurface),
+            specular: scalar(&self.specular),
+            specular_tint: scalar(&self.specular_tint),
+            roughness: scalar(&self.roughness).clamp(0.01, 1.),
+            anisotropic: scalar(&self.anisotropic),
+            sheen: scalar(&self.sheen),
+            sheen_tint: scalar(&self.sheen_tint),
+            clearcoat: scalar(&self.clearcoat),
+            clearcoat_gloss: scalar(&self.clearcoat_gloss),
+            spec_trans: scalar(&self.spec_trans),
+            eta: if rec.front_face { ior } else { 1. / ior },
+        }
+    }
+}
+
+/// `DisneyPrincipled` evaluated at a hit.
+struct PrincipledParams {
+    base_color: Vec3A,
+    metallic: f32,
+    subsurface: f32,
+    specular: f32,
+    specular_tint: f32,
+    roughness: f32,
+    anisotropic: f32,
+    sheen: f32,
+    sheen_tint: f32,
+    clearcoat: f32,
+    clearcoat_gloss: f32,
+    spec_trans: f32,
+    eta: f32,
+}
+
+impl PrincipledParams {
+    fn diffuse_weight(&self) -> f32 {
+        (1. - self.metallic) * (1. - self.spec_trans)
+    }
+
+    fn spec_weight(&self) -> f32 {
+        1. - (1. - self.metallic) * self.spec_trans
+    }
+
+    fn glass_weight(&self) -> f32 {
+        (1. - self.metallic) * self.spec_trans
+    }
+
+    /// Reflectance at normal incidence of the specular lobe.
+    fn spec_color(&self) -> Vec3A {
+        let luminance = luminance(self.base_color);
+        let c_tint = if luminance > 0. { self.base_color / luminance } else { Vec3A::ONE };
+        let dielectric = 0.08 * self.specular * Vec3A::ONE.lerp(c_tint, self.specular_tint);
+        dielectric.lerp(self.base_color, self.metallic)
+    }
+
+    /// Chances of sampling the diffuse, specular, clearcoat and glass lobes,
+    /// by a rough guess of how much each of them reflects towards `wi`.
+    fn lobe_probs(&self, wi: Vec3A) -> [f32; 4] {
+        let f = schlick_fresnel(wi.z);
+        let w = [
+            self.diffuse_weight() * luminance(self.base_color).max(self.sheen),
+            self.spec_weight() * luminance(self.spec_color().lerp(Vec3A::ONE, f)),
+            0.25 * self.clearcoat * lerp(0.04, 1., f),
+            self.glass_weight(),
+        ];
+        let sum: f32 = w.iter().sum();
+        if sum > 0. { w.map(|w| w / sum) } else { [0.; 4] }
+    }
+}
+
+impl Material for DisneyPrincipled {
+    fn flags(&self) -> LobeFlags {
+        LobeFlags::DIFFUSE | LobeFlags::GLOSSY | LobeFlags::REFLECTION | LobeFlags::TRANSMISSION
+    }
+
+    fn sample(&self, rec: &HitRecord, wi: Vec3A, uc: f32, u: Vec2) -> Option<BsdfSample> {
+        if wi.z <= 0. {
+            return None;
+        }
+        let p = self.params(rec);
+        let probs = p.lobe_probs(wi);
+        // pick a lobe with `uc` and reuse what is left of it for the glass lobe
+        let mut uc = uc;
+        let mut lobe = 0;
+        while lobe < 3 && uc >= probs[lobe] {
+            uc -= probs[lobe];
+            lobe += 1;
+        }
+        let wo = match lobe {
+            0 => sample_cosine_hemisphere(u),
+            1 => {
+                let (ax, ay) = disney_alpha(p.roughness, p.anisotropic);
+                reflect_local(wi, sample_gtr2_vndf(wi, ax, ay, u))
+            }
+            2 => reflect_local(wi, sample_gtr1(clearcoat_alpha(p.clearcoat_gloss), u)),
+            _ => {
+                let uc = (uc / probs[3]).min(1.);
+                rough_dielectric_sample_with(wi, p.eta, p.roughness, uc, u, self.flags(), |cos| self.glass_fresnel(rec, &p, cos))?
+            }
+        };
+        let pdf = self.pdf(rec, wi, wo);
+        if pdf == 0. {
+            return None;
+        }
+        let flags = if lobe == 0 {
+            LobeFlags::DIFFUSE | LobeFlags::REFLECTION
+        } else if wo.z < 0. {
+            LobeFlags::GLOSSY | LobeFlags::TRANSMISSION
+        } else {
+            LobeFlags::GLOSSY | LobeFlags::REFLECTION
+        };
+        Some(BsdfSample { wo, weight: self.eval(rec, wi, wo) / pdf, pdf, flags, exit: None })
+    }
+
+    fn eval(&self, rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> Vec3A {
+        if wi.z <= 0. || wo.z == 0. {
+            return Vec3A::ZERO;
+        }
+        let p = self.params(rec);
+        let glass = p.glass_weight() * rough_dielectric_eval_with(wi, wo, p.eta, p.roughness, |cos| self.glass_fresnel(rec, &p, cos));
+        if wo.z < 0. {
+            return p.base_color * glass;
+        }
+        let h = (wi + wo).normalize();
+        let h_dot_o = h.dot(wo);
+
+        let diffuse = p.base_color * disney_diffuse(wi, wo, p.roughness, p.subsurface)
+            + p.sheen * disney_sheen(p.base_color, p.sheen_tint, h_dot_o);
+
+        let (ax, ay) = disney_alpha(p.roughness, p.anisotropic);
+        let fs = match &self.film {
+            Some(film) => film.over_f0(rec, h_dot_o, p.spec_color()),
+            None => p.spec_color().lerp(Vec3A::ONE, schlick_fresnel(h_dot_o)),
+        };
+        let spec = fs * gtr2_ansio(h, ax, ay) * smith_geo_ggx_aniso(wi, ax, ay) * smith_geo_ggx_aniso(wo, ax, ay);
+
+        let clearcoat = p.clearcoat * disney_clearcoat(wi, wo, clearcoat_alpha(p.clearcoat_gloss));
+
+        (p.diffuse_weight() * diffuse + p.spec_weight() * spec + Vec3A::splat(clearcoat)) * wo.z + glass
+    }
+
+    fn pdf(&self, rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> f32 {
+        if wi.z <= 0. || wo.z == 0. {
+            return 0.;
+        }
+        let p = self.params(rec);
+        let probs = p.lobe_probs(wi);
+        let glass =
+            probs[3] * rough_dielectric_pdf_with(wi, wo, p.eta, p.roughness, self.flags(), |cos| self.glass_fresnel(rec, &p, cos));
+        if wo.z < 0. {
+            return glass;
+        }
+        let h = (wi + wo).normalize();
+        let (ax, ay) = disney_alpha(p.roughness, p.anisotropic);
+        let diffuse = cosine_hemisphere_pdf(wo.z);
+        let spec = reflect_pdf(gtr2_vndf_pdf(wi, h, ax, ay), h, wo);
+        let clearcoat = reflect_pdf(gtr1(h.z, clearcoat_alpha(p.clearcoat_gloss)) * h.z, h, wo);
+        probs[0] * diffuse + probs[1] * spec + probs[2] * clearcoat + glass
+    }
+}
+
+/// Dielectric interface between the outside and the coat of `Coated`, smooth below roughness 1e-3.
+/// Unlike the helpers above it works from both sides, seen from below it mirrors the directions and inverts `eta`.
+struct CoatInterface {
+    eta: f32,
+    roughness: f32,
+}
+
+/// `f` is the BSDF without the cosine, for a smooth interface it only makes sense divided by `pdf`.
+struct CoatSample {
+    wo: Vec3A,
+    f: f32,
+    pdf: f32,
+}
+
+impl CoatInterface {
+    fn is_smooth(&self) -> bool {
+        self.roughness < 1e-3
+    }
+
+    /// Mirrors `v` below the surface up, and gives the relative IOR seen from there.
+    fn above(&self, v: Vec3A) -> (f32, f32) {
+        if v.z < 0. { (-1., 1. / self.eta) } else { (1., self.eta) }
+    }
+
+    fn f(&self, wi: Vec3A, wo: Vec3A) -> f32 {
+        if self.is_smooth() || wo.z == 0. {
+            return 0.;
+        }
+        let (s, eta) = self.above(wi);
+        let flip = vec3a(1., 1., s);
+        rough_dielectric_eval(wi * flip, wo * flip, eta, self.roughness) / wo.z.abs()
+    }
+
+    fn pdf(&self, wi: Vec3A, wo: Vec3A, lobes: LobeFlags) -> f32 {
+        if self.is_smooth() {
+            return 0.;
+        }
+        let (s, eta) = self.above(wi);
+        let flip = vec3a(1., 1., s);
+        rough_dielectric_pdf(wi * flip, wo * flip, eta, self.roughness, lobes)
+    }
+
+    /// With `importance` the transmission isn't scaled by 1 / eta^2,
+    /// which gives the BSDF for light going the other way, from `wo` to `wi`.
+    fn sample(&self, wi: Vec3A, uc: f32, u: Vec2, lobes: LobeFlags, importance: bool) -> Option<CoatSample> {
+        let (s, eta) = self.above(wi);
+        let flip = vec3a(1., 1., s);
+        let wi = wi * flip;
+        let scale = |wo: Vec3A| if importance && wo.z < 0. { eta * eta } else { 1. };
+        let sample = if self.is_smooth() {
+            let f = fresnel_dielectric_2(wi.z, eta);
+            let pr = reflect_prob(Vec3A::splat(f), lobes)?;
+            if uc < pr {
+                let wo = vec3a(-wi.x, -wi.y, wi.z);
+                CoatSample { wo, f: f / wo.z, pdf: pr }
+            } else {
+                let wo = refract(-wi, Vec3A::Z, 1. / eta).normalize();
+                CoatSample { wo, f: scale(wo) * (1. - f) / (eta * eta * wo.z.abs()), pdf: 1. - pr }
+            }
+        } else {
+            let wo = rough_dielectric_sample(wi, eta, self.roughness, uc, u, lobes)?;
+            let f = scale(wo) * rough_dielectric_eval(wi, wo, eta, self.roughness) / wo.z.abs();
+            CoatSample { wo, f, pdf: rough_dielectric_pdf(wi, wo, eta, self.roughness, lobes) }
+        };
+        if sample.f == 0. || sample.pdf == 0. || sample.wo.z == 0. {
+            return None;
+        }
+        Some(CoatSample { wo: sample.wo * flip, ..sample })
+    }
+}
+
+const COAT_MAX_DEPTH: usize = 10;
+const COAT_SAMPLES: usize = 1;
+
+/// A clear dielectric coat over any other material, car paint or lacquered wood.
+/// Light refracts into the coat, bounces between the coat and `inner` and refracts back out,
+/// which `eval` and `pdf` estimate stochastically. Transmission through `inner` is dropped.
+/// Guo et al., "Position-Free Monte Carlo Simulation for Arbitrary Layered BSDFs", SIGGRAPH Asia 2018
+/// and the `LayeredBxDF` of pbrt-v4.
+pub struct Coated {
+    pub inner: Arc<dyn Material>,
+    pub ior: f32,
+    pub roughness: f32,
+    pub thickness: f32,
+    /// Beer-Lambert coefficient per unit of `thickness`.
+    pub absorption: Vec3A,
+}
+
+impl Coated {
+    fn interface(&self) -> CoatInterface {
+        CoatInterface { eta: self.ior, roughness: self.roughness }
+    }
+
+    /// Transmittance of one crossing of the coat along `w`.
+    fn tr(&self, w: Vec3A) -> Vec3A {
+        (-self.absorption * self.thickness / w.z.abs()).exp()
+    }
+}
+
+fn random_uc_u() -> (f32, Vec2) {
+    let u = vec3a_random();
+    (u.x, vec2(u.y, u.z))
+}
+
+impl Material for Coated {
+    fn flags(&self) -> LobeFlags {
+        let top = if self.interface().is_smooth() { LobeFlags::SPECULAR } else { LobeFlags::GLOSSY };
+        let inner = if self.inner.flags().is_non_specular() { LobeFlags::GLOSSY } else { LobeFlags::NONE };
+        top | inner | LobeFlags::REFLECTION
+    }
+
+    fn sample(&self, rec: &HitRecord, wi: Vec3A, uc: f32, u: Vec2) -> Option<BsdfSample> {
+        if wi.z <= 0. {
+            return None;
+        }
+        let top = self.interface();
+        let both = LobeFlags::REFLECTION | LobeFlags::TRANSMISSION;
+        let bs = top.sample(wi, uc, u, both, false)?;
+        let mut beta = Vec3A::splat(bs.f * bs.wo.z.abs() / bs.pdf);
+        if bs.wo.z > 0. {
+            return Some(if top.is_smooth() {
+                BsdfSample { wo: bs.wo, weight: beta, pdf: bs.pdf, flags: LobeFlags::SPECULAR | LobeFlags::REFLECTION, exit: None }
+            } else {
+                BsdfSample { wo: bs.wo, weight: beta, pdf: self.pdf(rec, wi, bs.wo), flags: LobeFlags::GLOSSY | LobeFlags::REFLECTION, exit: None }
+            });
+        }
+        let mut specular_path = top.is_smooth();
+        let mut w = bs.wo;
+        let mut at_bottom = false;
+        for depth in 0..COAT_MAX_DEPTH {
+            if depth > 3 && beta.max_element() < 0.25 {
+                let q = (1. - beta.max_element()).max(0.);
+                if RNG.with(|rng| rng.borrow_mut().gen::<f32>()) < q {
+                    return None;
+                }
+                beta /= 1. - q;
+            }
+            beta *= self.tr(w);
+            at_bottom = !at_bottom;
+            let (uc, u) = random_uc_u();
+            if at_bottom {
+                let bs = self.inner.sample(rec, -w, uc, u)?;
+                if bs.wo.z <= 0. {
+                    return None;
+                }
+                beta *= bs.weight;
+                specular_path &= bs.flags.is_specular();
+                w = bs.wo;
+            } else {
+                let bs = top.sample(-w, uc, u, both, false)?;
+                beta *= bs.f * bs.wo.z.abs() / bs.pdf;
+                w = bs.wo;
+                if w.z > 0. {
+                    // the integrator weighs the sample by the marginal density, not by the one of this path
+                    return Some(if specular_path {
+                        BsdfSample { wo: w, weight: beta, pdf: 1., flags: LobeFlags::SPECULAR | LobeFlags::REFLECTION, exit: None }
+                    } else {
+                        BsdfSample { wo: w, weight: beta, pdf: self.pdf(rec, wi, w), flags: LobeFlags::GLOSSY | LobeFlags::REFLECTION, exit: None }
+                    });
+                }
+            }
+        }
+        None
+    }
+
+    fn eval(&self, rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> Vec3A {
+        if wi.z <= 0. || wo.z <= 0. {
+            return Vec3A::ZERO;
+        }
+        let top = self.interface();
+        let inner_specular = !self.inner.flags().is_non_specular();
+        let mut f = Vec3A::splat(COAT_SAMPLES as f32 * top.f(wi, wo));
+        for _ in 0..COAT_SAMPLES {
+            // refract in along wi, and pick a way out towards wo for next event estimation inside the coat
+            let (uc, u) = random_uc_u();
+            let wos = match top.sample(wi, uc, u, LobeFlags::TRANSMISSION, false) {
+                Some(s) => s,
+                None => continue,
+            };
+            let (uc, u) = random_uc_u();
+            let wis = match top.sample(wo, uc, u, LobeFlags::TRANSMISSION, true) {
+                Some(s) => s,
+                None => continue,
+            };
+            let mut beta = Vec3A::splat(wos.f * wos.wo.z.abs() / wos.pdf);
+            let mut w = wos.wo;
+            let mut at_bottom = false;
+            for depth in 0..COAT_MAX_DEPTH {
+                if depth > 3 && beta.max_element() < 0.25 {
+                    let q = (1. - beta.max_element()).max(0.);
+                    if RNG.with(|rng| rng.borrow_mut().gen::<f32>()) < q {
+                        break;
+                    }
+                    beta /= 1. - q;
+                }
+                beta *= self.tr(w);
+                at_bottom = !at_bottom;
+                let (uc, u) = random_uc_u();
+                if at_bottom {
+                    if !inner_specular {
+                        let wt = if top.is_smooth() { 1. } else { power_heuristic(wis.pdf, self.inner.pdf(rec, -w, -wis.wo)) };
+                        f += beta * self.inner.eval(rec, -w, -wis.wo) * wt * self.tr(wis.wo) * wis.f / wis.pdf;
+                    }
+                    let bs = match self.inner.sample(rec, -w, uc, u) {
+                        Some(bs) if bs.wo.z > 0. => bs,
+                        _ => break,
+                    };
+                    beta *= bs.weight;
+                    w = bs.wo;
+                    if !top.is_smooth() {
+                        let f_exit = top.f(-w, wo);
+                        if f_exit > 0. {
+                            let wt = if inner_specular { 1. } else { power_heuristic(bs.pdf, top.pdf(wo, -w, LobeFlags::TRANSMISSION)) };
+                            f += beta * self.tr(w) * f_exit * wt;
+                        }
+                    }
+                } else {
+                    let bs = match top.sample(-w, uc, u, LobeFlags::REFLECTION, false) {
+                        Some(bs) => bs,
+                        None => break,
+                    };
+                    beta *= bs.f * bs.wo.z.abs() / bs.pdf;
+                    w = bs.wo;
+                }
+            }
+        }
+        f / COAT_SAMPLES as f32 * wo.z
+    }
+
+    fn pdf(&self, rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> f32 {
+        if wi.z <= 0. || wo.z <= 0. {
+            return 0.;
+        }
+        let top = self.interface();
+        let both = LobeFlags::REFLECTION | LobeFlags::TRANSMISSION;
+        let mut pdf_sum = COAT_SAMPLES as f32 * top.pdf(wi, wo, both);
+        for _ in 0..COAT_SAMPLES {
+            let (uc, u) = random_uc_u();
+            let wos = top.sample(wi, uc, u, LobeFlags::TRANSMISSION, false);
+            let (uc, u) = random_uc_u();
+            let wis = top.sample(wo, uc, u, LobeFlags::TRANSMISSION, true);
+            let (wos, wis) = match (wos, wis) {
+                (Some(wos), Some(wis)) => (wos, wis),
+                _ => continue,
+            };
+            if top.is_smooth() {
+                // the inner density is over directions in the coat, refracting out spreads them by this
+                let jacobian = wo.z / (self.ior * self.ior * wis.wo.z.abs());
+                pdf_sum += self.inner.pdf(rec, -wos.wo, -wis.wo) * jacobian;
+                continue;
+            }
+            let (uc, u) = random_uc_u();
+            if let Some(rs) = self.inner.sample(rec, -wos.wo, uc, u) {
+                if self.inner.flags().is_non_specular() {
+                    let r_pdf = self.inner.pdf(rec, -wos.wo, -wis.wo);
+                    let t_pdf = top.pdf(wis.wo, wo, both);
+                    pdf_sum += power_heuristic(wis.pdf, r_pdf) * r_pdf * t_pdf / wis.pdf;
+                    let t_pdf = top.pdf(-rs.wo, wo, both);
+                    pdf_sum += power_heuristic(rs.pdf, top.pdf(wo, -rs.wo, LobeFlags::TRANSMISSION)) * t_pdf;
+                } else {
+                    pdf_sum += top.pdf(-rs.wo, wo, both);
+                }
+            }
+        }
+        // mixed with a uniform density, the estimate above misses paths
+        lerp(1. / (4. * PI), pdf_sum / COAT_SAMPLES as f32, 0.9)
     }
 }
@@ -0,0 +1,126 @@
+use std::f32::consts::*;
+use std::sync::Arc;
+
+use glam::*;
+use crate::hitable::HitRecord;
+use crate::material::*;
+use crate::math::*;
+use crate::texture::Texture;
+
+/// Angular distribution of light scattered in a medium. Like `Material` `wi` points back along
+/// the incoming ray and `wo` away, so forward scattering sends `wo` towards `-wi`.
+pub trait PhaseFunction: Send + Sync {
+    /// Density of scattering into `wo`, which integrates to 1 over the sphere.
+    fn p(&self, wi: Vec3A, wo: Vec3A) -> f32;
+    /// Picks `wo` with density `p`.
+    fn sample(&self, wi: Vec3A, u: Vec2) -> Vec3A;
+}
+
+/// Direction at cosine `cos_theta` from `-wi` and azimuth `2 pi u`.
+fn around_forward(wi: Vec3A, cos_theta: f32, u: f32) -> Vec3A {
+    let f = -wi;
+    let t = coordinate_system(f);
+    let b = f.cross(t);
+    let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+    let phi = 2. * PI * u;
+    sin_theta * phi.cos() * t + sin_theta * phi.sin() * b + cos_theta * f
+}
+
+fn henyey_greenstein(cos_theta: f32, g: f32) -> f32 {
+    let denom = 1. + g * g - 2. * g * cos_theta;
+    (1. - g * g) / (4. * PI * denom * denom.max(1e-12).sqrt())
+}
+
+fn sample_henyey_greenstein(wi: Vec3A, g: f32, u: Vec2) -> Vec3A {
+    let cos_theta = if g.abs() < 1e-3 {
+        1. - 2. * u.x
+    } else {
+        let s = (1. - g * g) / (1. - g + 2. * g * u.x);
+        ((1. + g * g - s * s) / (2. * g)).clamp(-1., 1.)
+    };
+    around_forward(wi, cos_theta, u.y)
+}
+
+/// Henyey-Greenstein, `g` in (-1, 1) is the mean cosine, positive for forward scattering like haze around lights.
+pub struct HenyeyGreenstein {
+    pub g: f32,
+}
+
+impl PhaseFunction for HenyeyGreenstein {
+    fn p(&self, wi: Vec3A, wo: Vec3A) -> f32 {
+        henyey_greenstein(-wi.dot(wo), self.g)
+    }
+
+    fn sample(&self, wi: Vec3A, u: Vec2) -> Vec3A {
+        sample_henyey_greenstein(wi, self.g, u)
+    }
+}
+
+/// Blend of two Henyey-Greenstein lobes, `w` of `g1` and the rest of `g2`, usually one forward and one back
+/// like in clouds.
+pub struct DoubleHenyeyGreenstein {
+    pub g1: f32,
+    pub g2: f32,
+    pub w: f32,
+}
+
+impl PhaseFunction for DoubleHenyeyGreenstein {
+    fn p(&self, wi: Vec3A, wo: Vec3A) -> f32 {
+        let cos_theta = -wi.dot(wo);
+        lerp(henyey_greenstein(cos_theta, self.g2), henyey_greenstein(cos_theta, self.g1), self.w)
+    }
+
+    fn sample(&self, wi: Vec3A, u: Vec2) -> Vec3A {
+        if u.x < self.w {
+            sample_henyey_greenstein(wi, self.g1, vec2(u.x / self.w, u.y))
+        } else {
+            sample_henyey_greenstein(wi, self.g2, vec2((u.x - self.w) / (1. - self.w), u.y))
+        }
+    }
+}
+
+/// Scattering off particles much smaller than the wavelength, e.g. the molecules of a clear sky.
+pub struct Rayleigh;
+
+impl PhaseFunction for Rayleigh {
+    fn p(&self, wi: Vec3A, wo: Vec3A) -> f32 {
+        let cos_theta = wi.dot(wo);
+        3. / (16. * PI) * (1. + cos_theta * cos_theta)
+    }
+
+    /// Inverts the cubic CDF in closed form.
+    fn sample(&self, wi: Vec3A, u: Vec2) -> Vec3A {
+        let q = 4. * u.x - 2.;
+        let c = (q + (q * q + 1.).sqrt()).cbrt();
+        around_forward(wi, (c - 1. / c).clamp(-1., 1.), u.y)
+    }
+}
+
+/// Like `Isotropic` but scattering by `phase`.
+pub struct Anisotropic {
+    pub albedo: Arc<dyn Texture>,
+    pub phase: Arc<dyn PhaseFunction>,
+}
+
+impl Material for Anisotropic {
+    fn flags(&self) -> LobeFlags {
+        LobeFlags::DIFFUSE | LobeFlags::REFLECTION | LobeFlags::TRANSMISSION
+    }
+
+    fn eval(&self, rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> Vec3A {
+        self.albedo.value(rec.uv, rec.p) * self.phase.p(wi, wo)
+    }
+
+    fn sample(&self, rec: &HitRecord, wi: Vec3A, _uc: f32, u: Vec2) -> Option<BsdfSample> {
+        let wo = self.phase.sample(wi, u).normalize();
+        let pdf = self.phase.p(wi, wo);
+        if pdf == 0. {
+            return None;
+        }
+        Some(BsdfSample { wo, weight: self.albedo.value(rec.uv, rec.p), pdf, flags: self.flags(), exit: None })
+    }
+
+    fn pdf(&self, _rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> f32 {
+        self.phase.p(wi, wo)
+    }
+}
@@ -0,0 +1,246 @@
+use std::f32::consts::*;
+
+use glam::*;
+use once_cell::sync::OnceCell;
+
+use crate::lib::SKY_COLOR;
+use crate::math::*;
+
+/// Angular radius of the sun seen from the earth, in radians.
+const SUN_ANGULAR_RADIUS: f32 = 0.004651;
+/// Luminance of the sun above the atmosphere, in the kcd/m² of the sky model.
+const SUN_LUMINANCE: f32 = 2.0e6;
+/// Rows of the table the sky is sampled by, over the polar angle. There are twice as many columns over the azimuth.
+const SKY_TABLE_RES: usize = 64;
+
+/// The sky of a scene set up with `set_sun_sky`, which the integrator also samples directly.
+pub static SUN_SKY: OnceCell<SunSky> = OnceCell::new();
+
+fn sun_sky_color(d: Vec3A) -> Vec3A {
+    SUN_SKY.get().unwrap().radiance(d)
+}
+
+/// Makes `sky` the environment of the scene, both looked up by rays leaving it and sampled as a light.
+pub fn set_sun_sky(sky: SunSky) {
+    if SUN_SKY.set(sky).is_err() {
+        panic!("sun sky is already set");
+    }
+    SKY_COLOR.set(sun_sky_color).unwrap();
+}
+
+/// Perez et al. luminance distribution relative to the zenith, `p` holding the coefficients A to E.
+fn perez(p: &[f32; 5], cos_theta: f32, gamma: f32) -> f32 {
+    let cos_gamma = gamma.cos();
+    (1. + p[0] * (p[1] / cos_theta.max(0.01)).exp()) * (1. + p[2] * (p[3] * gamma).exp() + p[4] * cos_gamma * cos_gamma)
+}
+
+fn xyy_to_rgb(x: f32, y: f32, lum: f32) -> Vec3A {
+    if y <= 0. {
+        return Vec3A::ZERO;
+    }
+    let xyz = vec3a(x / y * lum, lum, (1. - x - y) / y * lum);
+    vec3a(
+        3.2406 * xyz.x - 1.5372 * xyz.y - 0.4986 * xyz.z,
+        -0.9689 * xyz.x + 1.8758 * xyz.y + 0.0415 * xyz.z,
+        0.0557 * xyz.x - 0.2040 * xyz.y + 1.0570 * xyz.z,
+    ).max(Vec3A::ZERO)
+}
+
+fn luminance(c: Vec3A) -> f32 {
+    vec3a(0.2126, 0.7152, 0.0722).dot(c)
+}
+
+/// Piecewise constant density over `[0, 1)`, sampled by inverting its CDF.
+struct Piecewise {
+    func: Vec<f32>,
+    cdf: Vec<f32>,
+    integral: f32,
+}
+
+impl Piecewise {
+    fn new(func: Vec<f32>) -> Self {
+        let n = func.len();
+        let mut cdf = vec![0.; n + 1];
+        for i in 0..n {
+            cdf[i + 1] = cdf[i] + func[i] / n as f32;
+        }
+        let integral = cdf[n];
+        for (i, c) in cdf.iter_mut().enumerate() {
+            *c = if integral > 0. { *c / integral } else { i as f32 / n as f32 };
+        }
+        Self { func, cdf, integral }
+    }
+
+    /// Point in `[0, 1)` and the piece it is in.
+    fn sample(&self, u: f32) -> (f32, usize) {
+        let n = self.func.len();
+        let i = (self.cdf.partition_point(|&c| c <= u).max(1) - 1).min(n - 1);
+        let width = self.cdf[i + 1] - self.cdf[i];
+        let du = if width > 0. { (u - self.cdf[i]) / width } else { 0. };
+        ((i as f32 + du.clamp(0., 1.)) / n as f32, i)
+    }
+}
+
+/// Preetham et al., "A Practical Analytic Model for Daylight", SIGGRAPH 1999, with the sun as a disk on top
+/// reddened by the air it goes through. The model stops at the horizon, below it is a diffuse ground of `albedo`
+/// lit by the sky and sun. +y is up and the azimuth goes from +x towards +z.
+pub struct SunSky {
+    pub sun_dir: Vec3A,
+    pub turbidity: f32,
+    pub albedo: Vec3A,
+    /// Converts the kcd/m² of the model to the radiance of the scene.
+    pub scale: f32,
+    perez: [[f32; 5]; 3],
+    /// Y, x and y at the zenith, each over its Perez function towards the zenith.
+    zenith: Vec3A,
+    sun_radiance: Vec3A,
+    ground: Vec3A,
+    cos_sun_max: f32,
+    /// Share of samples aimed at the sun rather than by the table.
+    sun_prob: f32,
+    marginal: Piecewise,
+    conditional: Vec<Piecewise>,
+}
+
+impl SunSky {
+    /// The sun at `elevation` above the horizon and `azimuth`, in degrees. `turbidity` goes from 2 for a clear sky
+    /// to about 10 for haze.
+    pub fn new(elevation: f32, azimuth: f32, turbidity: f32, albedo: Vec3A, scale: f32) -> Self {
+        let theta_s = (90. - elevation.clamp(0., 90.)).to_radians();
+        let phi_s = azimuth.to_radians();
+        let sun_dir = vec3a(theta_s.sin() * phi_s.cos(), theta_s.cos(), theta_s.sin() * phi_s.sin());
+        let t = turbidity;
+
+        let chi = (4. / 9. - t / 120.) * (PI - 2. * theta_s);
+        let zenith_lum = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let th = vec3a(theta_s * theta_s * theta_s, theta_s * theta_s, theta_s);
+        let zenith_x = t * t * th.dot(vec3a(0.00166, -0.00375, 0.00209))
+            + t * (th.dot(vec3a(-0.02903, 0.06377, -0.03202)) + 0.00394)
+            + th.dot(vec3a(0.11693, -0.21196, 0.06052)) + 0.25886;
+        let zenith_y = t * t * th.dot(vec3a(0.00275, -0.00610, 0.00317))
+            + t * (th.dot(vec3a(-0.04214, 0.08970, -0.04153)) + 0.00516)
+            + th.dot(vec3a(0.15346, -0.26756, 0.06670)) + 0.26688;
+        let coeffs = [
+            [0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251, 0.1206 * t - 2.5771, -0.0670 * t + 0.3703],
+            [-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125, -0.0641 * t - 0.8989, -0.0033 * t + 0.0452],
+            [-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102, -0.0441 * t - 1.6537, -0.0109 * t + 0.0529],
+        ];
+        let zenith = vec3a(
+            zenith_lum / perez(&coeffs[0], 1., theta_s),
+            zenith_x / perez(&coeffs[1], 1., theta_s),
+            zenith_y / perez(&coeffs[2], 1., theta_s),
+        );
+
+        // Rayleigh and Angstrom aerosol optical depths at the wavelengths of red, green and blue, in micrometers
+        let lambda = vec3a(0.68, 0.55, 0.44);
+        let air_mass = 1. / (theta_s.cos() + 0.15 * (93.885 - theta_s.to_degrees()).powf(-1.253));
+        let rayleigh = 0.008735 * lambda.powf(-4.08);
+        let aerosol = (0.04608 * t - 0.04586) * lambda.powf(-1.3);
+        let sun_radiance = SUN_LUMINANCE * scale * (-(rayleigh + aerosol) * air_mass).exp();
+        let cos_sun_max = SUN_ANGULAR_RADIUS.cos();
+
+        let mut sky = Self {
+            sun_dir,
+            turbidity,
+            albedo,
+            scale,
+            perez: coeffs,
+            zenith,
+            sun_radiance,
+            ground: Vec3A::ZERO,
+            cos_sun_max,
+            sun_prob: 0.,
+            marginal: Piecewise::new(vec![1.]),
+            conditional: Vec::new(),
+        };
+
+        // the ground reflects what the sky and sun shine on it
+        let (rows, cols) = (SKY_TABLE_RES, 2 * SKY_TABLE_RES);
+        let d_omega = |i: usize| PI / rows as f32 * 2. * PI / cols as f32 * ((i as f32 + 0.5) / rows as f32 * PI).sin();
+        let mut irradiance = sun_radiance * 2. * PI * (1. - cos_sun_max) * sun_dir.y;
+        for i in 0..rows / 2 {
+            for j in 0..cols {
+                let d = Self::cell_dir(i, j, rows, cols);
+                irradiance += sky.sky(d) * d.y * d_omega(i);
+            }
+        }
+        sky.ground = albedo * irradiance / PI;
+
+        let mut sky_power = 0.;
+        sky.conditional = (0..rows).map(|i| {
+            let sin_theta = ((i as f32 + 0.5) / rows as f32 * PI).sin();
+            Piecewise::new((0..cols).map(|j| {
+                let f = luminance(sky.sky(Self::cell_dir(i, j, rows, cols)));
+                sky_power += f * d_omega(i);
+                f * sin_theta
+            }).collect())
+        }).collect();
+        sky.marginal = Piecewise::new(sky.conditional.iter().map(|c| c.integral).collect());
+        let sun_power = luminance(sun_radiance) * 2. * PI * (1. - cos_sun_max);
+        sky.sun_prob = (sun_power / (sun_power + sky_power)).clamp(0.1, 0.9);
+        sky
+    }
+
+    fn cell_dir(i: usize, j: usize, rows: usize, cols: usize) -> Vec3A {
+        let theta = (i as f32 + 0.5) / rows as f32 * PI;
+        let phi = (j as f32 + 0.5) / cols as f32 * 2. * PI;
+        vec3a(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin())
+    }
+
+    /// Sky or ground without the sun.
+    fn sky(&self, d: Vec3A) -> Vec3A {
+        if d.y < 0. {
+            return self.ground;
+        }
+        let gamma = d.dot(self.sun_dir).clamp(-1., 1.).acos();
+        let lum = self.zenith.x * perez(&self.perez[0], d.y, gamma);
+        let x = self.zenith.y * perez(&self.perez[1], d.y, gamma);
+        let y = self.zenith.z * perez(&self.perez[2], d.y, gamma);
+        self.scale * xyy_to_rgb(x, y, lum)
+    }
+
+    pub fn radiance(&self, d: Vec3A) -> Vec3A {
+        if d.y >= 0. && d.dot(self.sun_dir) >= self.cos_sun_max {
+            return self.sky(d) + self.sun_radiance;
+        }
+        self.sky(d)
+    }
+
+    /// Direction towards the sun or picked by the brightness of the sky.
+    pub fn sample(&self) -> Vec3A {
+        let u = vec3a_random();
+        if u.x < self.sun_prob {
+            let z = 1. + u.y * (self.cos_sun_max - 1.);
+            let sin = (1. - z * z).max(0.).sqrt();
+            let (sin_phi, cos_phi) = (2. * PI * u.z).sin_cos();
+            let t = coordinate_system(self.sun_dir);
+            let b = self.sun_dir.cross(t);
+            return t * (cos_phi * sin) + b * (sin_phi * sin) + self.sun_dir * z;
+        }
+        let (v, i) = self.marginal.sample(u.y);
+        let (w, _) = self.conditional[i].sample(u.z);
+        let (theta, phi) = (v * PI, w * 2. * PI);
+        vec3a(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin())
+    }
+
+    /// Solid angle density of `sample` returning the normalized `d`.
+    pub fn pdf(&self, d: Vec3A) -> f32 {
+        let sun = if d.y >= 0. && d.dot(self.sun_dir) >= self.cos_sun_max {
+            1. / (2. * PI * (1. - self.cos_sun_max))
+        } else {
+            0.
+        };
+        // from x and z rather than y, which rounds to one well before the sine gets to zero
+        let sin_theta = (d.x * d.x + d.z * d.z).sqrt();
+        let theta = sin_theta.atan2(d.y);
+        if sin_theta <= 0. || self.marginal.integral <= 0. {
+            return self.sun_prob * sun;
+        }
+        let phi = d.z.atan2(d.x).rem_euclid(2. * PI);
+        let (rows, cols) = (self.conditional.len(), self.conditional[0].func.len());
+        let i = ((theta / PI * rows as f32) as usize).min(rows - 1);
+        let j = ((phi / (2. * PI) * cols as f32) as usize).min(cols - 1);
+        let table = self.conditional[i].func[j] / self.marginal.integral / (2. * PI * PI * sin_theta);
+        self.sun_prob * sun + (1. - self.sun_prob) * table
+    }
+}
@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use glam::*;
+
+use crate::math::*;
+use crate::volume::{DensityField, VolumeError};
+
+/// Voxels along each side of a brick, the unit the grid is stored and skipped by.
+pub const BRICK_SIZE: i32 = 8;
+const BRICK_VOXELS: usize = (BRICK_SIZE * BRICK_SIZE * BRICK_SIZE) as usize;
+
+/// Sparse grid of densities stored as bricks of `BRICK_SIZE` cubed voxels, like the leaves of a VDB tree,
+/// with empty space simply missing. Voxel `ijk` is centered at `origin + (ijk + 0.5) * voxel_size`
+/// and densities between voxel centers are interpolated trilinearly.
+pub struct SparseGrid {
+    origin: Vec3A,
+    voxel_size: f32,
+    bricks: HashMap<IVec3, usize>,
+    data: Vec<[f32; BRICK_VOXELS]>,
+    /// Tight bounds of where the density is nonzero.
+    pub bounds: AABB,
+    /// One cell per brick, covering the bricks from `majorant_min`.
+    majorant_grid: Vec<f32>,
+    majorant_min: IVec3,
+    majorant_res: IVec3,
+    max_value: f32,
+    /// Multiplies the stored values into an extinction coefficient.
+    pub scale: f32,
+}
+
+/// Brick holding voxel `ijk` and the index of the voxel in it.
+fn brick_of(ijk: IVec3) -> (IVec3, usize) {
+    let b = ivec3(ijk.x.div_euclid(BRICK_SIZE), ijk.y.div_euclid(BRICK_SIZE), ijk.z.div_euclid(BRICK_SIZE));
+    let l = ijk - b * BRICK_SIZE;
+    (b, ((l.z * BRICK_SIZE + l.y) * BRICK_SIZE + l.x) as usize)
+}
+
+impl SparseGrid {
+    /// Grid from bricks given by their brick coordinates, voxels x fastest, then y, then z.
+    pub fn from_bricks(origin: Vec3A, voxel_size: f32, bricks: Vec<(IVec3, [f32; BRICK_VOXELS])>, scale: f32) -> Self {
+        let mut grid = Self {
+            origin,
+            voxel_size,
+            bricks: HashMap::new(),
+            data: Vec::with_capacity(bricks.len()),
+            bounds: AABB { min: origin, max: origin },
+            majorant_grid: Vec::new(),
+            majorant_min: IVec3::ZERO,
+            majorant_res: IVec3::ZERO,
+            max_value: 0.,
+            scale,
+        };
+        for (b, voxels) in bricks {
+            match grid.bricks.get(&b) {
+                Some(&i) => grid.data[i] = voxels,
+                None => {
+                    grid.bricks.insert(b, grid.data.len());
+                    grid.data.push(voxels);
+                }
+            }
+        }
+        grid.build_majorants();
+        grid
+    }
+
+    /// Grid from individual voxels, e.g. to build a volume in code. Voxels not given are empty.
+    pub fn from_voxels(origin: Vec3A, voxel_size: f32, voxels: impl IntoIterator<Item = (IVec3, f32)>, scale: f32) -> Self {
+        let mut bricks: HashMap<IVec3, [f32; BRICK_VOXELS]> = HashMap::new();
+        for (ijk, v) in voxels {
+            let (b, i) = brick_of(ijk);
+            bricks.entry(b).or_insert([0.; BRICK_VOXELS])[i] = v;
+        }
+        Self::from_bricks(origin, voxel_size, bricks.into_iter().collect(), scale)
+    }
+
+    /// Reads a sparse grid file, all little endian: the magic `SVOX`, a u32 version of 1, the origin as 3 f32,
+    /// the voxel size as f32 and a u32 brick count, then per brick its brick coordinates as 3 i32
+    /// followed by its `BRICK_SIZE` cubed f32 voxels, x fastest.
+    pub fn load<P: AsRef<Path>>(path: P, scale: f32) -> Result<Self, VolumeError> {
+        let path = path.as_ref();
+        let bytes = fs::read(path).map_err(|e| VolumeError::Io(path.to_path_buf(), e))?;
+        let format_error = |msg: &str| VolumeError::Format(path.to_path_buf(), msg.to_string());
+        let word = |i: usize| -> [u8; 4] { bytes[i..i + 4].try_into().unwrap() };
+        if bytes.len() < 28 || &bytes[..4] != b"SVOX" {
+            return Err(format_error("not a sparse grid file"));
+        }
+        if u32::from_le_bytes(word(4)) != 1 {
+            return Err(format_error("unsupported version"));
+        }
+        let origin = vec3a(f32::from_le_bytes(word(8)), f32::from_le_bytes(word(12)), f32::from_le_bytes(word(16)));
+        let voxel_size = f32::from_le_bytes(word(20));
+        if !voxel_size.is_finite() || voxel_size <= 0. {
+            return Err(format_error("voxel size has to be positive"));
+        }
+        let count = u32::from_le_bytes(word(24)) as usize;
+        let brick_bytes = 12 + 4 * BRICK_VOXELS;
+        if bytes.len() < 28 + count * brick_bytes {
+            return Err(format_error("truncated brick data"));
+        }
+        let bricks = (0..count).map(|n| {
+            let at = 28 + n * brick_bytes;
+            let b = ivec3(i32::from_le_bytes(word(at)), i32::from_le_bytes(word(at + 4)), i32::from_le_bytes(word(at + 8)));
+            let mut voxels = [0.; BRICK_VOXELS];
+            for (i, v) in voxels.iter_mut().enumerate() {
+                *v = f32::from_le_bytes(word(at + 12 + 4 * i));
+            }
+            (b, voxels)
+        }).collect();
+        Ok(Self::from_bricks(origin, voxel_size, bricks, scale))
+    }
+
+    pub fn brick_count(&self) -> usize {
+        self.data.len()
+    }
+
+    fn voxel(&self, ijk: IVec3) -> f32 {
+        let (b, i) = brick_of(ijk);
+        match self.bricks.get(&b) {
+            Some(&n) => self.data[n][i],
+            None => 0.,
+        }
+    }
+
+    fn to_world(&self, index: Vec3A) -> Vec3A {
+        self.origin + index * self.voxel_size
+    }
+
+    /// Finds the tight bounds and the majorant of each brick sized cell, which has to cover every voxel
+    /// interpolated inside it, so one more on each side reaching into the neighbouring bricks.
+    fn build_majorants(&mut self) {
+        let mut brick_max = HashMap::new();
+        let (mut lo, mut hi) = (IVec3::splat(i32::MAX), IVec3::splat(i32::MIN));
+        for (&b, &n) in &self.bricks {
+            let mut m = 0f32;
+            for (i, &v) in self.data[n].iter().enumerate() {
+                if v > 0. {
+                    let i = i as i32;
+                    let ijk = b * BRICK_SIZE + ivec3(i % BRICK_SIZE, i / BRICK_SIZE % BRICK_SIZE, i / (BRICK_SIZE * BRICK_SIZE));
+                    lo = lo.min(ijk);
+                    hi = hi.max(ijk);
+                    m = m.max(v);
+                }
+            }
+            if m > 0. {
+                brick_max.insert(b, m);
+                self.max_value = self.max_value.max(m);
+            }
+        }
+        if brick_max.is_empty() {
+            return;
+        }
+        // a voxel's density fades out at the centers of its neighbours
+        self.bounds = AABB {
+            min: self.to_world(Vec3A::from(lo.as_vec3()) - 0.5),
+            max: self.to_world(Vec3A::from(hi.as_vec3()) + 1.5),
+        };
+        let (b_lo, _) = brick_of(lo);
+        let (b_hi, _) = brick_of(hi);
+        self.majorant_min = b_lo - 1;
+        self.majorant_res = b_hi - b_lo + 3;
+        let res = self.majorant_res;
+        self.majorant_grid = vec![0.; (res.x * res.y * res.z) as usize];
+        for (&b, &m) in &brick_max {
+            for dz in -1..=1 {
+                for dy in -1..=1 {
+                    for dx in -1..=1 {
+                        let c = b - self.majorant_min + ivec3(dx, dy, dz);
+                        let cell = &mut self.majorant_grid[((c.z * res.y + c.y) * res.x + c.x) as usize];
+                        *cell = cell.max(m);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl DensityField for SparseGrid {
+    fn density(&self, p: Vec3A) -> f32 {
+        if p.cmplt(self.bounds.min).any() || p.cmpgt(self.bounds.max).any() {
+            return 0.;
+        }
+        let g = (p - self.origin) / self.voxel_size - 0.5;
+        let i = g.floor();
+        let f = g - i;
+        let i = i.as_ivec3();
+        let d = |x: i32, y: i32, z: i32| self.voxel(i + ivec3(x, y, z));
+        let d00 = lerp(d(0, 0, 0), d(1, 0, 0), f.x);
+        let d10 = lerp(d(0, 1, 0), d(1, 1, 0), f.x);
+        let d01 = lerp(d(0, 0, 1), d(1, 0, 1), f.x);
+        let d11 = lerp(d(0, 1, 1), d(1, 1, 1), f.x);
+        self.scale * lerp(lerp(d00, d10, f.y), lerp(d01, d11, f.y), f.z)
+    }
+
+    fn majorant(&self) -> f32 {
+        self.scale * self.max_value
+    }
+
+    /// Steps through the majorant grid with a 3D DDA, so empty bricks cost nothing
+    /// and thin parts are tracked with their own majorant.
+    fn majorants(&self, r: &Ray, t_min: f32, t_max: f32, f: &mut dyn FnMut(f32, f32, f32) -> bool) {
+        if self.majorant_grid.is_empty() {
+            return;
+        }
+        let (t0, t1) = match self.bounds.clip(r, t_min, t_max) {
+            Some(t) => t,
+            None => return,
+        };
+        let cell_size = self.voxel_size * BRICK_SIZE as f32;
+        let grid_origin = self.to_world(Vec3A::from((self.majorant_min * BRICK_SIZE).as_vec3()));
+        let q = (r.at(t0) - grid_origin) / cell_size;
+        let res = self.majorant_res;
+        let mut cell = q.floor().as_ivec3().clamp(IVec3::ZERO, res - 1);
+        let mut step = IVec3::ZERO;
+        let mut t_next = Vec3A::splat(f32::INFINITY);
+        let mut t_delta = Vec3A::splat(f32::INFINITY);
+        for axis in 0..3 {
+            let dq = r.d[axis] / cell_size;
+            if dq > 0. {
+                step[axis] = 1;
+                t_next[axis] = t0 + ((cell[axis] + 1) as f32 - q[axis]) / dq;
+                t_delta[axis] = 1. / dq;
+            } else if dq < 0. {
+                step[axis] = -1;
+                t_next[axis] = t0 + (cell[axis] as f32 - q[axis]) / dq;
+                t_delta[axis] = -1. / dq;
+            }
+        }
+        let mut t = t0;
+        loop {
+            let axis = if t_next.x < t_next.y && t_next.x < t_next.z { 0 } else if t_next.y < t_next.z { 1 } else { 2 };
+            let t_exit = t_next[axis].min(t1);
+            if t_exit > t {
+                let majorant = self.majorant_grid[((cell.z * res.y + cell.y) * res.x + cell.x) as usize];
+                if f(t, t_exit, self.scale * majorant) {
+                    return;
+                }
+                t = t_exit;
+            }
+            if t >= t1 {
+                return;
+            }
+            cell[axis] += step[axis];
+            if cell[axis] < 0 || cell[axis] >= res[axis] {
+                return;
+            }
+            t_next[axis] += t_delta[axis];
+        }
+    }
+}
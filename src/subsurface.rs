@@ -0,0 +1,190 @@
+use std::sync::Arc;
+
+use glam::*;
+use rand::Rng;
+
+use crate::hitable::{Hitable, HitRecord};
+use crate::lib::RNG;
+use crate::material::*;
+use crate::math::*;
+use crate::pbr::RoughDielectric;
+
+/// Steps after which a walk is given up as absorbed.
+const WALK_MAX_STEPS: usize = 256;
+
+/// Single scattering albedo giving roughly the multiple scattering `albedo` `a` of a flat slab.
+/// Chiang et al., "Practical and Controllable Subsurface Scattering for Production Path Tracing", SIGGRAPH 2016
+fn single_scattering_albedo(a: Vec3A) -> Vec3A {
+    let s = 4.09712 + 4.20863 * a - (9.59217 + 41.6808 * a + 17.7126 * a * a).powf(0.5);
+    1. - s * s
+}
+
+/// Random walk subsurface scattering inside `boundary`, for skin, wax and marble.
+/// Like `ConstantMedium` it samples free flights inside the boundary, here with a mean free path per channel,
+/// but the whole walk runs when a path refracts in, and the path goes on from where it refracts back out,
+/// sampling the lights there through a diffuse lobe.
+/// The walk only sees `boundary`, which has to be closed. It runs in the space of `boundary`,
+/// so the mean free path scales with it when the `Subsurface` is placed by a `Transform` or instanced.
+pub struct Subsurface {
+    boundary: Arc<dyn Hitable>,
+    mat: Arc<dyn Material>,
+}
+
+impl Subsurface {
+    /// `albedo` is the color of the lit object and `mfp` the mean free path per channel in scene units.
+    /// The interface is smooth below `roughness` 1e-3, and the material of `boundary` is unused.
+    pub fn new(boundary: Arc<dyn Hitable>, albedo: Vec3A, mfp: Vec3A, ior: f32, roughness: f32) -> Self {
+        let sigma_t = mfp.max(Vec3A::splat(1e-6)).recip();
+        let sigma_s = single_scattering_albedo(albedo.clamp(Vec3A::ZERO, Vec3A::ONE)) * sigma_t;
+        let surface: Arc<dyn Material> = if roughness < 1e-3 {
+            Arc::new(Dielectric { ior, film: None, medium: None })
+        } else {
+            Arc::new(RoughDielectric { ior, roughness, absorption: Vec3A::ZERO, film: None })
+        };
+        let mat = Arc::new(RandomWalk { boundary: boundary.clone(), surface, exit: Arc::new(WalkExit), sigma_s, sigma_t });
+        Self { boundary, mat }
+    }
+}
+
+impl Hitable for Subsurface {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32, rec: &mut HitRecord) -> bool {
+        if !self.boundary.hit(r, t_min, t_max, rec) {
+            return false;
+        }
+        rec.mat = Some(self.mat.clone());
+        // the walk runs in this space, whatever places `boundary` inside it
+        rec.obj_to_world = Affine3A::IDENTITY;
+        true
+    }
+
+    fn bbox(&self, aabb: &mut AABB) -> bool {
+        self.boundary.bbox(aabb)
+    }
+
+    fn memo(&self) -> String {
+        format!("Subsurface of {}", self.boundary.memo())
+    }
+}
+
+/// Surface of a `Subsurface`. Reflections are those of `surface`, refractions walk through the medium,
+/// picking the channel to sample each flight with by the throughput so far and weighing the flight
+/// by the mixture of the channels.
+struct RandomWalk {
+    boundary: Arc<dyn Hitable>,
+    surface: Arc<dyn Material>,
+    exit: Arc<dyn Material>,
+    sigma_s: Vec3A,
+    sigma_t: Vec3A,
+}
+
+impl RandomWalk {
+    /// Walks from `p` along `d` until `surface` lets the path out, returning the exit as a hit
+    /// of `WalkExit` facing the inside, the direction the path reached it with and the throughput.
+    fn walk(&self, mut p: Vec3A, mut d: Vec3A) -> Option<(HitRecord, Vec3A, Vec3A)> {
+        let mut beta = Vec3A::ONE;
+        for step in 0..WALK_MAX_STEPS {
+            if step > 3 && beta.max_element() < 0.25 {
+                let q = (1. - beta.max_element()).max(0.);
+                if RNG.with(|rng| rng.borrow_mut().gen::<f32>()) < q {
+                    return None;
+                }
+                beta /= 1. - q;
+            }
+            let sum = beta.x + beta.y + beta.z;
+            if sum <= 0. {
+                return None;
+            }
+            let channel_pdf = beta / sum;
+            let u = vec3a_random();
+            let channel = if u.x < channel_pdf.x { 0 } else if u.x < channel_pdf.x + channel_pdf.y { 1 } else { 2 };
+            let t = -(1. - u.y).ln() / self.sigma_t[channel];
+
+            let mut rec = HitRecord::default();
+            if !self.boundary.hit(&Ray { o: p, d, s: Vec2::ZERO }, 0., f32::MAX, &mut rec) {
+                return None;
+            }
+            if t < rec.t {
+                let tr = (-self.sigma_t * t).exp();
+                beta *= self.sigma_s * tr / (channel_pdf * self.sigma_t * tr).dot(Vec3A::ONE);
+                p += t * d;
+                d = sample_uniform_sphere(vec2(u.z, RNG.with(|rng| rng.borrow_mut().gen::<f32>())));
+                continue;
+            }
+            let tr = (-self.sigma_t * rec.t).exp();
+            beta *= tr / (channel_pdf * tr).dot(Vec3A::ONE);
+            let u = vec3a_random();
+            let bs = self.surface.sample(&rec, rec.world_to_local(-d), u.x, vec2(u.y, u.z))?;
+            beta *= bs.weight;
+            // the normal of `rec` faces the inside
+            if bs.wo.z > 0. {
+                d = rec.local_to_world(bs.wo).normalize();
+                p = offset_hit_point(rec.p, rec.geom_norm);
+            } else {
+                rec.p = offset_hit_point(rec.p, -rec.geom_norm);
+                rec.mat = Some(self.exit.clone());
+                return Some((rec, d, beta));
+            }
+        }
+        None
+    }
+}
+
+impl Material for RandomWalk {
+    fn flags(&self) -> LobeFlags {
+        self.surface.flags()
+    }
+
+    fn eval(&self, rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> Vec3A {
+        if wo.z > 0. { self.surface.eval(rec, wi, wo) } else { Vec3A::ZERO }
+    }
+
+    fn sample(&self, rec: &HitRecord, wi: Vec3A, uc: f32, u: Vec2) -> Option<BsdfSample> {
+        let bs = self.surface.sample(rec, wi, uc, u)?;
+        // seen from inside, e.g. by a camera in there, it is just the interface
+        if bs.wo.z > 0. || !rec.front_face {
+            return Some(bs);
+        }
+        let d = rec.local_to_world(bs.wo).normalize();
+        let world_to_obj = rec.obj_to_world.inverse();
+        let p = world_to_obj.transform_point3a(offset_hit_point(rec.p, -rec.geom_norm));
+        let (mut exit, d, beta) = self.walk(p, world_to_obj.transform_vector3a(d).normalize())?;
+        exit.p = rec.obj_to_world.transform_point3a(exit.p);
+        exit.obj_to_world = Affine3A::IDENTITY;
+        exit.transform_frame(&rec.obj_to_world, &world_to_obj.matrix3.transpose());
+        let d = rec.obj_to_world.transform_vector3a(d).normalize();
+        Some(BsdfSample { wo: rec.world_to_local(d), weight: bs.weight * beta, exit: Some(exit), ..bs })
+    }
+
+    fn pdf(&self, rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> f32 {
+        if wo.z > 0. { self.surface.pdf(rec, wi, wo) } else { 0. }
+    }
+}
+
+/// Where a walk leaves through `RandomWalk::surface`, which already decided it gets out.
+/// After bouncing around inside, the way out hardly depends on the way in, so a cosine lobe
+/// stands in for the refraction and gives the exit a density to weigh light samples against.
+struct WalkExit;
+
+impl Material for WalkExit {
+    fn flags(&self) -> LobeFlags {
+        LobeFlags::DIFFUSE | LobeFlags::TRANSMISSION
+    }
+
+    fn eval(&self, rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> Vec3A {
+        Vec3A::splat(self.pdf(rec, wi, wo))
+    }
+
+    fn sample(&self, _rec: &HitRecord, _wi: Vec3A, _uc: f32, u: Vec2) -> Option<BsdfSample> {
+        let wo = sample_cosine_hemisphere(u) * vec3a(1., 1., -1.);
+        let pdf = cosine_hemisphere_pdf(wo.z);
+        if pdf <= 0. {
+            return None;
+        }
+        Some(BsdfSample { wo, weight: Vec3A::ONE, pdf, flags: self.flags(), exit: None })
+    }
+
+    // the normal faces the inside, so the lobe is below it
+    fn pdf(&self, _rec: &HitRecord, _wi: Vec3A, wo: Vec3A) -> f32 {
+        if wo.z < 0. { cosine_hemisphere_pdf(wo.z) } else { 0. }
+    }
+}
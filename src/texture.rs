@@ -10,6 +10,10 @@ use crate::lib::RNG;
 
 pub trait Texture: Send + Sync {
     fn value(&self, uv: Vec2, p: Vec3A) -> Vec3A;
+    /// Texels along u and v of a texture looked up from an image, which `BumpMap` steps over.
+    fn resolution(&self) -> Option<UVec2> {
+        None
+    }
 }
 
 pub struct ConstantTex {
@@ -50,7 +54,7 @@ impl Texture for CheckerTex {
 
 const PERLIN_POINT_COUNT: usize = 256;
 
-struct Perlin {
+pub struct Perlin {
     rand_vec: Vec<Vec3A>,
     perm_x: Vec<usize>,
     perm_y: Vec<usize>,
@@ -173,11 +177,23 @@ pub struct ImageTex {
 
 impl ImageTex {
     pub fn new(path: String) -> Self {
-        let img = image::open(path).unwrap();
+        Self::load(path).unwrap()
+    }
+
+    /// `data` is tightly packed RGB in `[0, 1]`.
+    pub fn from_rgb32f(width: u32, height: u32, data: Vec<f32>) -> Option<Self> {
+        let img = ImageBuffer::from_raw(width, height, data)?;
+        Some(Self {
+            img,
+        })
+    }
+
+    pub fn load<P: AsRef<std::path::Path>>(path: P) -> ImageResult<Self> {
+        let img = image::open(path)?;
         let img = img.to_rgb32f();
-        Self {
+        Ok(Self {
             img,
-        }
+        })
     }
 }
 
@@ -190,4 +206,8 @@ impl Texture for ImageTex {
         let rgb = self.img.get_pixel(i, j);
         vec3a(rgb[0], rgb[1], rgb[2])
     }
+
+    fn resolution(&self) -> Option<UVec2> {
+        Some(uvec2(self.img.width(), self.img.height()))
+    }
 }
\ No newline at end of file
@@ -0,0 +1,344 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use glam::*;
+use once_cell::sync::OnceCell;
+use rand::Rng;
+
+use crate::hitable::{Hitable, HitRecord};
+use crate::lib::RNG;
+use crate::material::*;
+use crate::math::*;
+use crate::phase::{Anisotropic, PhaseFunction};
+use crate::texture::{ConstantTex, Perlin, Texture};
+
+/// Spatially varying extinction coefficient of a `HeterogeneousMedium`.
+pub trait DensityField: Send + Sync {
+    fn density(&self, p: Vec3A) -> f32;
+    /// Upper bound of `density` everywhere, the rate of the tentative collisions of delta tracking.
+    fn majorant(&self) -> f32;
+    /// Splits `t_min..t_max` along `r` into pieces with a majorant each, tighter than the global one,
+    /// and calls `f(t0, t1, majorant)` on them in order until it returns true.
+    fn majorants(&self, _r: &Ray, t_min: f32, t_max: f32, f: &mut dyn FnMut(f32, f32, f32) -> bool) {
+        f(t_min, t_max, self.majorant());
+    }
+}
+
+/// Billowing density from Perlin turbulence, `density` where it is strong and empty where it is weak.
+pub struct NoiseDensity {
+    pub perlin: Perlin,
+    pub scale: f32,
+    pub density: f32,
+}
+
+impl NoiseDensity {
+    pub fn new(scale: f32, density: f32) -> Self {
+        Self { perlin: Perlin::default(), scale, density }
+    }
+}
+
+impl DensityField for NoiseDensity {
+    fn density(&self, p: Vec3A) -> f32 {
+        self.density * (2. * self.perlin.turb(self.scale * p) - 0.5).clamp(0., 1.)
+    }
+
+    fn majorant(&self) -> f32 {
+        self.density
+    }
+}
+
+#[derive(Debug)]
+pub enum VolumeError {
+    Io(PathBuf, std::io::Error),
+    Format(PathBuf, String),
+}
+
+impl fmt::Display for VolumeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VolumeError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            VolumeError::Format(path, msg) => write!(f, "{}: {}", path.display(), msg),
+        }
+    }
+}
+
+impl std::error::Error for VolumeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VolumeError::Io(_, e) => Some(e),
+            VolumeError::Format(..) => None,
+        }
+    }
+}
+
+/// Dense grid of densities over the box `min`..`max`, one sample at the center of each voxel,
+/// interpolated trilinearly and zero outside the box.
+pub struct VoxelGrid {
+    pub min: Vec3A,
+    pub max: Vec3A,
+    res: [usize; 3],
+    data: Vec<f32>,
+    max_value: f32,
+    /// Multiplies the stored values into an extinction coefficient.
+    pub scale: f32,
+}
+
+impl VoxelGrid {
+    /// `data` is x fastest, then y, then z.
+    pub fn new(min: Vec3A, max: Vec3A, res: [usize; 3], data: Vec<f32>, scale: f32) -> Self {
+        assert_eq!(data.len(), res[0] * res[1] * res[2]);
+        let max_value = data.iter().cloned().fold(0., f32::max);
+        Self { min, max, res, data, max_value, scale }
+    }
+
+    /// Reads a Mitsuba `.vol` grid of float32 samples, keeping the first channel of each voxel.
+    pub fn load<P: AsRef<Path>>(path: P, scale: f32) -> Result<Self, VolumeError> {
+        let path = path.as_ref();
+        let bytes = fs::read(path).map_err(|e| VolumeError::Io(path.to_path_buf(), e))?;
+        let format_error = |msg: &str| VolumeError::Format(path.to_path_buf(), msg.to_string());
+        if bytes.len() < 48 || &bytes[..3] != b"VOL" || bytes[3] != 3 {
+            return Err(format_error("not a version 3 VOL file"));
+        }
+        let int = |i: usize| i32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        let float = |i: usize| f32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        if int(4) != 1 {
+            return Err(format_error("only float32 grids are supported"));
+        }
+        let (nx, ny, nz, channels) = (int(8), int(12), int(16), int(20));
+        if nx <= 0 || ny <= 0 || nz <= 0 || channels <= 0 {
+            return Err(format_error("empty grid"));
+        }
+        let res = [nx as usize, ny as usize, nz as usize];
+        let channels = channels as usize;
+        // a corrupt header must not wrap around and pass the length check
+        let count = res[0].checked_mul(res[1]).and_then(|n| n.checked_mul(res[2]));
+        let size = count.and_then(|n| n.checked_mul(4 * channels)).and_then(|n| n.checked_add(48));
+        let (count, size) = match (count, size) {
+            (Some(count), Some(size)) => (count, size),
+            _ => return Err(format_error("grid too large")),
+        };
+        if bytes.len() < size {
+            return Err(format_error("truncated voxel data"));
+        }
+        let min = vec3a(float(24), float(28), float(32));
+        let max = vec3a(float(36), float(40), float(44));
+        let data = (0..count).map(|i| float(48 + 4 * i * channels)).collect();
+        Ok(Self::new(min, max, res, data, scale))
+    }
+
+    fn voxel(&self, x: usize, y: usize, z: usize) -> f32 {
+        self.data[(z * self.res[1] + y) * self.res[0] + x]
+    }
+}
+
+impl DensityField for VoxelGrid {
+    fn density(&self, p: Vec3A) -> f32 {
+        let res = vec3a(self.res[0] as f32, self.res[1] as f32, self.res[2] as f32);
+        let g = (p - self.min) / (self.max - self.min) * res - 0.5;
+        if g.cmplt(Vec3A::splat(-0.5)).any() || g.cmpgt(res - 0.5).any() {
+            return 0.;
+        }
+        let g = g.clamp(Vec3A::ZERO, res - 1.);
+        let i = g.floor();
+        let f = g - i;
+        let (x, y, z) = (i.x as usize, i.y as usize, i.z as usize);
+        let (x1, y1, z1) = ((x + 1).min(self.res[0] - 1), (y + 1).min(self.res[1] - 1), (z + 1).min(self.res[2] - 1));
+        let d00 = lerp(self.voxel(x, y, z), self.voxel(x1, y, z), f.x);
+        let d10 = lerp(self.voxel(x, y1, z), self.voxel(x1, y1, z), f.x);
+        let d01 = lerp(self.voxel(x, y, z1), self.voxel(x1, y, z1), f.x);
+        let d11 = lerp(self.voxel(x, y1, z1), self.voxel(x1, y1, z1), f.x);
+        self.scale * lerp(lerp(d00, d10, f.y), lerp(d01, d11, f.y), f.z)
+    }
+
+    fn majorant(&self) -> f32 {
+        self.scale * self.max_value
+    }
+}
+
+/// Phase function of a `HeterogeneousMedium`, which also glows where it absorbs.
+struct VolumeScatter {
+    scatter: Anisotropic,
+    emission: Option<Arc<dyn Texture>>,
+}
+
+impl Material for VolumeScatter {
+    fn flags(&self) -> LobeFlags {
+        self.scatter.flags()
+    }
+
+    fn eval(&self, rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> Vec3A {
+        self.scatter.eval(rec, wi, wo)
+    }
+
+    fn sample(&self, rec: &HitRecord, wi: Vec3A, uc: f32, u: Vec2) -> Option<BsdfSample> {
+        self.scatter.sample(rec, wi, uc, u)
+    }
+
+    fn pdf(&self, rec: &HitRecord, wi: Vec3A, wo: Vec3A) -> f32 {
+        self.scatter.pdf(rec, wi, wo)
+    }
+
+    /// Collisions are found in proportion to extinction, so the absorbed fraction of them emits.
+    fn emitted(&self, uv: Vec2, p: Vec3A) -> Vec3A {
+        match &self.emission {
+            Some(e) => (1. - self.scatter.albedo.value(uv, p)) * e.value(uv, p),
+            None => Vec3A::ZERO,
+        }
+    }
+}
+
+/// Participating medium inside `boundary` with density, albedo and emission varying in space, for clouds and smoke.
+/// Collisions are found by delta tracking against the majorants of `density`, so shadow rays through it
+/// see it exactly as often as it blocks them.
+pub struct HeterogeneousMedium {
+    boundary: Arc<dyn Hitable>,
+    density: Arc<dyn DensityField>,
+    phase_fn: Arc<dyn Material>,
+}
+
+impl HeterogeneousMedium {
+    /// `albedo` and `emission` are solid textures looked up at the collision. `boundary` has to be closed
+    /// and its material is unused.
+    pub fn new(
+        boundary: Arc<dyn Hitable>,
+        density: Arc<dyn DensityField>,
+        albedo: Arc<dyn Texture>,
+        emission: Option<Arc<dyn Texture>>,
+        phase: Arc<dyn PhaseFunction>,
+    ) -> Self {
+        let phase_fn = Arc::new(VolumeScatter { scatter: Anisotropic { albedo, phase }, emission });
+        Self { boundary, density, phase_fn }
+    }
+}
+
+impl Hitable for HeterogeneousMedium {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32, rec: &mut HitRecord) -> bool {
+        let mut rec_1 = HitRecord::default();
+        if !self.boundary.hit(r, f32::NEG_INFINITY, f32::INFINITY, &mut rec_1) {
+            return false;
+        }
+        let mut rec_2 = HitRecord::default();
+        if !self.boundary.hit(r, rec_1.t + 0.0001, f32::INFINITY, &mut rec_2) {
+            return false;
+        }
+        let t_start = rec_1.t.max(t_min).max(0.);
+        let t_end = rec_2.t.min(t_max);
+        if t_start >= t_end {
+            return false;
+        }
+        let ray_len = r.d.length();
+        let mut hit_t = None;
+        self.density.majorants(r, t_start, t_end, &mut |t0, t1, majorant| {
+            if majorant <= 0. {
+                return false;
+            }
+            let step = 1. / (majorant * ray_len);
+            let mut t = t0;
+            loop {
+                let (u_t, u_accept) = RNG.with(|rng| {
+                    let mut rng = rng.borrow_mut();
+                    (rng.gen::<f32>(), rng.gen::<f32>())
+                });
+                t -= (1. - u_t).ln() * step;
+                // free flights are memoryless, so the next piece starts afresh at its own majorant
+                if t >= t1 {
+                    return false;
+                }
+                // tentative collisions with the rest of the majorant are null and the flight goes on
+                if u_accept * majorant < self.density.density(r.at(t)) {
+                    hit_t = Some(t);
+                    return true;
+                }
+            }
+        });
+        let t = match hit_t {
+            Some(t) => t,
+            None => return false,
+        };
+        rec.t = t;
+        rec.p = r.at(t);
+        rec.uv = Vec2::ZERO;
+        rec.norm = Vec3A::X;
+        rec.tang = Vec3A::Y;
+        rec.geom_norm = Vec3A::X;
+        rec.front_face = true;
+        rec.mat = Some(self.phase_fn.clone());
+        true
+    }
+
+    fn bbox(&self, aabb: &mut AABB) -> bool {
+        self.boundary.bbox(aabb)
+    }
+
+    fn memo(&self) -> String {
+        format!("HeterogeneousMedium in {}", self.boundary.memo())
+    }
+
+    fn transformed_bbox(&self, xform: &Affine3A, aabb: &mut AABB) -> bool {
+        self.boundary.transformed_bbox(xform, aabb)
+    }
+}
+
+/// Medium around the objects of a scene, like fog, which paths leaving the interior of an object return to.
+pub static SCENE_MEDIUM: OnceCell<Arc<Medium>> = OnceCell::new();
+/// Medium the camera is in when it isn't `SCENE_MEDIUM`, e.g. under water.
+pub static CAMERA_MEDIUM: OnceCell<Arc<Medium>> = OnceCell::new();
+
+/// Homogeneous medium filling space rather than a boundary, which paths carry along: the scene medium,
+/// the one around the camera or the inside of a `Dielectric`, see `Material::interior`.
+pub struct Medium {
+    pub sigma_a: Vec3A,
+    pub sigma_s: Vec3A,
+    phase_fn: Arc<dyn Material>,
+}
+
+impl Medium {
+    /// Absorption and scattering coefficients per unit length, per channel.
+    pub fn new(sigma_a: Vec3A, sigma_s: Vec3A, phase: Arc<dyn PhaseFunction>) -> Self {
+        let phase_fn = Arc::new(Anisotropic { albedo: Arc::new(ConstantTex { col: Vec3A::ONE }), phase });
+        Self { sigma_a, sigma_s, phase_fn }
+    }
+
+    fn sigma_t(&self) -> Vec3A {
+        self.sigma_a + self.sigma_s
+    }
+
+    /// Fraction of light getting through `dist`, all of it in channels the medium leaves alone.
+    pub fn transmittance(&self, dist: f32) -> Vec3A {
+        let sigma_t = self.sigma_t();
+        Vec3A::select(sigma_t.cmpgt(Vec3A::ZERO), (-sigma_t * dist).exp(), Vec3A::ONE)
+    }
+
+    /// Samples the distance to the next scattering along a normalized ray with a channel picked uniformly,
+    /// `None` if it is past `t_max` where the ray meets a surface. Also returns the throughput up to there,
+    /// weighed by the density of all three channels.
+    pub fn sample_distance(&self, t_max: f32, u: Vec2) -> (Option<f32>, Vec3A) {
+        let sigma_t = self.sigma_t();
+        let channel = ((u.x * 3.) as usize).min(2);
+        let t = if sigma_t[channel] > 0. { -(1. - u.y).ln() / sigma_t[channel] } else { f32::INFINITY };
+        if t < t_max {
+            let tr = self.transmittance(t);
+            let pdf = (sigma_t * tr).dot(Vec3A::ONE) / 3.;
+            return (Some(t), self.sigma_s * tr / pdf);
+        }
+        let tr = self.transmittance(t_max);
+        let pdf = tr.dot(Vec3A::ONE) / 3.;
+        (None, if pdf > 0. { tr / pdf } else { Vec3A::ZERO })
+    }
+
+    /// Hit record of a scattering at `t` along `r`, whose material is the phase function.
+    pub fn scatter_record(&self, r: &Ray, t: f32) -> HitRecord {
+        HitRecord {
+            t,
+            p: r.at(t),
+            norm: Vec3A::X,
+            tang: Vec3A::Y,
+            geom_norm: Vec3A::X,
+            front_face: true,
+            mat: Some(self.phase_fn.clone()),
+            ..HitRecord::default()
+        }
+    }
+}